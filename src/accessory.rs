@@ -0,0 +1,135 @@
+// Identification of the sensor board connected, by the resistor on the
+// last pin of the sensor connector
+
+use embassy_time::Instant;
+
+use crate::config::*;
+use crate::midi::U7;
+
+pub struct Accessory {
+    pub name: &'static str,
+    // ADC reading range (permile of full scale) identifying the accessory
+    pub adc_min: u32,
+    pub adc_max: u32,
+    pub root_note: i8,
+    // mapping of sensors to MIDI notes (MIDI number of the root note will be added)
+    pub notes: [Option<i8>; NUM_SENSORS],
+    pub piano_keys: [PianoKey; NUM_SENSORS],
+    pub leds: [Option<usize>; NUM_SENSORS],
+    // range (permile) the sensitivity knob is mapped to
+    pub sensitivity_min: u32,
+    pub sensitivity_max: u32,
+}
+
+impl Accessory {
+    /// Find the accessory identified by the ADC reading, the first one
+    /// in `ACCESSORIES` is the default.
+    pub fn identify(adc_value: u32) -> &'static Accessory {
+        ACCESSORIES
+            .iter()
+            .find(|a| (a.adc_min..=a.adc_max).contains(&adc_value))
+            .unwrap_or(&ACCESSORIES[0])
+    }
+    /// MIDI note number assigned to sensor `i`, if any
+    pub fn note(&self, i: usize) -> Option<U7> {
+        let note_nr = self.root_note.checked_add(self.notes[i]?)?;
+        U7::try_from(note_nr).ok()
+    }
+    /// Map the sensitivity knob position (permile) to the accessory range
+    pub fn sensitivity(&self, knob: u32) -> u32 {
+        let range = self.sensitivity_max.saturating_sub(self.sensitivity_min);
+        self.sensitivity_min + range * knob.min(1000) / 1000
+    }
+}
+
+/// Debounced accessory detection, so hot-swapping is noticed only when
+/// the new reading is stable.
+pub struct AccessoryDetector {
+    current: &'static Accessory,
+    candidate: &'static Accessory,
+    candidate_since: Instant,
+}
+
+impl AccessoryDetector {
+    pub fn new(adc_value: u32) -> Self {
+        let current = Accessory::identify(adc_value);
+        Self {
+            current,
+            candidate: current,
+            candidate_since: Instant::now(),
+        }
+    }
+    pub fn current(&self) -> &'static Accessory {
+        self.current
+    }
+    /// Feed a new ADC reading, returns true when the accessory has changed
+    pub fn update(&mut self, adc_value: u32, now: Instant) -> bool {
+        let accessory = Accessory::identify(adc_value);
+        if !core::ptr::eq(accessory, self.candidate) {
+            self.candidate = accessory;
+            self.candidate_since = now;
+        }
+        if !core::ptr::eq(self.candidate, self.current)
+            && now - self.candidate_since >= ACCESSORY_DEBOUNCE
+        {
+            self.current = self.candidate;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn identify_by_the_adc_reading() {
+        assert!(Accessory::identify(950).name == "fruit octave");
+        assert!(Accessory::identify(250).name == "drum pad mat");
+        assert!(Accessory::identify(500).name == "two-octave strip");
+        // between the ranges, the default
+        assert!(Accessory::identify(750).name == ACCESSORIES[0].name);
+    }
+
+    #[test]
+    fn notes_and_sensitivity() {
+        let accessory = &ACCESSORIES[0];
+        assert!(accessory.note(1) == U7::new(60));
+        let drums = Accessory::identify(250);
+        assert_eq!(drums.sensitivity(0), 100);
+        assert_eq!(drums.sensitivity(500), 350);
+        assert_eq!(drums.sensitivity(2000), 600);
+    }
+
+    #[test]
+    fn swap_noticed_when_stable() {
+        let start = Instant::now();
+        let mut detector = AccessoryDetector::new(950);
+        assert!(detector.current().name == "fruit octave");
+        assert!(!detector.update(250, start));
+        let almost = start + ACCESSORY_DEBOUNCE - Duration::from_millis(1);
+        assert!(!detector.update(250, almost));
+        assert!(detector.update(250, start + ACCESSORY_DEBOUNCE));
+        assert!(detector.current().name == "drum pad mat");
+        // reported once
+        assert!(!detector.update(250, start + ACCESSORY_DEBOUNCE * 2));
+    }
+
+    #[test]
+    fn glitches_are_ignored() {
+        let start = Instant::now();
+        let mut detector = AccessoryDetector::new(950);
+        let mut now = start;
+        for _ in 0..10 {
+            // a reading off now and then restarts the wait
+            assert!(!detector.update(250, now));
+            now += ACCESSORY_DEBOUNCE / 2;
+            assert!(!detector.update(950, now));
+            now += ACCESSORY_DEBOUNCE / 2;
+        }
+        assert!(detector.current().name == "fruit octave");
+    }
+}
@@ -0,0 +1,103 @@
+// Polyphonic key pressure from the analog level of held sensors
+
+use embassy_time::Instant;
+
+use crate::config::*;
+use crate::midi::U7;
+
+#[derive(Clone, Copy)]
+struct KeyPressure {
+    pressure: u8,
+    sent_at: Instant,
+}
+
+pub struct AftertouchTracker {
+    keys: [Option<KeyPressure>; NUM_SENSORS],
+}
+
+impl Default for AftertouchTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AftertouchTracker {
+    pub fn new() -> Self {
+        Self {
+            keys: [None; NUM_SENSORS],
+        }
+    }
+    /// Forget the state of sensor `i`, call it when the key is touched or released
+    pub fn reset(&mut self, i: usize) {
+        self.keys[i] = None;
+    }
+    /// Pressure to send for held sensor `i`, if it changed enough and the
+    /// previous one was not sent too recently.
+    ///
+    /// `level` and `threshold_level` are in permile (see `TouchSensors::level()`).
+    pub fn update(
+        &mut self,
+        i: usize,
+        level: u32,
+        threshold_level: u32,
+        now: Instant,
+    ) -> Option<U7> {
+        let pressure = if threshold_level < 1000 {
+            (level.saturating_sub(threshold_level) * 127 / (1000 - threshold_level)).min(127) as u8
+        } else {
+            0
+        };
+        if let Some(key) = self.keys[i] {
+            if now < key.sent_at + AFTERTOUCH_INTERVAL
+                || pressure.abs_diff(key.pressure) < AFTERTOUCH_DEAD_BAND
+            {
+                return None;
+            }
+        }
+        self.keys[i] = Some(KeyPressure {
+            pressure,
+            sent_at: now,
+        });
+        Some(U7::saturating(pressure as i32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn pressure_from_the_level_past_the_threshold() {
+        let mut tracker = AftertouchTracker::new();
+        let now = Instant::from_secs(1);
+        assert!(tracker.update(0, 500, 500, now) == Some(U7::MIN));
+        assert!(tracker.update(1, 1000, 500, now) == Some(U7::MAX));
+        assert!(tracker.update(2, 750, 500, now) == Some(U7::saturating(63)));
+        assert!(tracker.update(3, 1000, 1000, now) == Some(U7::MIN));
+    }
+
+    #[test]
+    fn rate_limited() {
+        let mut tracker = AftertouchTracker::new();
+        let now = Instant::from_secs(1);
+        assert!(tracker.update(0, 600, 500, now).is_some());
+        let soon = now + AFTERTOUCH_INTERVAL - Duration::from_millis(1);
+        assert!(tracker.update(0, 1000, 500, soon).is_none());
+        let later = now + AFTERTOUCH_INTERVAL;
+        assert!(tracker.update(0, 1000, 500, later) == Some(U7::MAX));
+    }
+
+    #[test]
+    fn small_changes_are_not_sent() {
+        let mut tracker = AftertouchTracker::new();
+        let now = Instant::from_secs(1);
+        assert!(tracker.update(0, 1000, 500, now).is_some());
+        let later = now + AFTERTOUCH_INTERVAL;
+        // one step below the maximum, within the dead band
+        assert!(tracker.update(0, 997, 500, later).is_none());
+        tracker.reset(0);
+        assert!(tracker.update(0, 997, 500, later).is_some());
+    }
+}
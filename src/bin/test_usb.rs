@@ -7,7 +7,9 @@ use embassy_futures::join::join;
 use embassy_time::Timer;
 use {defmt_rtt as _, panic_probe as _};
 
-use touch_keyboard::midi::{MidiChannel, MidiMsg};
+use touch_keyboard::config::MIDI_OUT_CHANNEL;
+use touch_keyboard::midi::{MidiChannel, MidiMsg, U7};
+use touch_keyboard::midi_router::ConnectionSignal;
 use touch_keyboard::usb_midi::UsbMidi;
 
 #[embassy_executor::main]
@@ -22,21 +24,24 @@ async fn main(_spawner: Spawner) {
     led.set_high();
 
     let midi_channel = MidiChannel::new();
-    let mut usb_midi = UsbMidi::new(b.core1.midi_usb, midi_channel.receiver());
+    let connection = ConnectionSignal::new();
+    let mut usb_midi = UsbMidi::new(b.core1.midi_usb, midi_channel.receiver(), &connection);
 
     let send_task = async {
         loop {
             midi_channel
                 .send(MidiMsg::NoteOn {
-                    note: 60,
-                    velocity: 64,
+                    channel: MIDI_OUT_CHANNEL,
+                    note: U7::saturating(60),
+                    velocity: U7::saturating(64),
                 })
                 .await;
             Timer::after_millis(100).await;
             midi_channel
                 .send(MidiMsg::NoteOff {
-                    note: 60,
-                    velocity: 0,
+                    channel: MIDI_OUT_CHANNEL,
+                    note: U7::saturating(60),
+                    velocity: U7::MIN,
                 })
                 .await;
             Timer::after_millis(1000).await;
@@ -15,18 +15,51 @@ pub type MidiTxPin = PIN_0;
 
 pub type MidiUsb = USB;
 
+#[cfg(not(feature = "pio-sensors"))]
+pub type SensorPins = [Flex<'static>; NUM_SENSORS];
+
+#[cfg(feature = "pio-sensors")]
+pub type SensorsPio = PIO1;
+#[cfg(feature = "pio-sensors")]
+pub type SensorsDma = DMA_CH0;
+
+// the pins must be consecutive GPIOs, so a single state machine can handle them
+#[cfg(feature = "pio-sensors")]
+pub struct SensorPins {
+    pub pio: embassy_rp::pio::Pio<'static, SensorsPio>,
+    pub pins: [embassy_rp::pio::Pin<'static, SensorsPio>; NUM_SENSORS],
+    pub dma: SensorsDma,
+}
+
 bind_interrupts!(pub struct Irqs {
     UART0_IRQ => embassy_rp::uart::BufferedInterruptHandler<MidiUart>;
     USBCTRL_IRQ => embassy_rp::usb::InterruptHandler<USB>;
 });
 
+#[cfg(feature = "pio-sensors")]
+bind_interrupts!(pub struct SensorsIrqs {
+    PIO1_IRQ_0 => embassy_rp::pio::InterruptHandler<SensorsPio>;
+});
+
+#[cfg(feature = "mpr121-sensors")]
+pub type Mpr121I2c = I2C1;
+
+#[cfg(feature = "mpr121-sensors")]
+bind_interrupts!(pub struct Mpr121Irqs {
+    I2C1_IRQ => embassy_rp::i2c::InterruptHandler<Mpr121I2c>;
+});
+
 pub struct Core0Pers {
     pub button_in: Input<'static>,
 
     pub leds_pio: LedsPio,
     pub leds_pin: LedsPin,
 
-    pub sensor_pins: [Flex<'static>; NUM_SENSORS],
+    pub sensor_pins: SensorPins,
+    #[cfg(feature = "mpr121-sensors")]
+    pub mpr121_i2c: embassy_rp::i2c::I2c<'static, Mpr121I2c, embassy_rp::i2c::Async>,
+    #[cfg(feature = "mpr121-sensors")]
+    pub mpr121_irq: Input<'static>,
 
     pub adc: ADC,
     pub adc_pins: AdcPins,
@@ -57,6 +90,7 @@ pub fn init(p: Peripherals) -> BoardSetup {
             leds_pio: p.PIO0,
             leds_pin: p.PIN_27,
 
+            #[cfg(not(feature = "pio-sensors"))]
             sensor_pins: [
                 Flex::new(p.PIN_6),
                 Flex::new(p.PIN_7),
@@ -75,6 +109,43 @@ pub fn init(p: Peripherals) -> BoardSetup {
                 Flex::new(p.PIN_20),
                 Flex::new(p.PIN_21),
             ],
+            #[cfg(feature = "pio-sensors")]
+            sensor_pins: {
+                let mut pio = embassy_rp::pio::Pio::new(p.PIO1, SensorsIrqs);
+                let pins = [
+                    pio.common.make_pio_pin(p.PIN_6),
+                    pio.common.make_pio_pin(p.PIN_7),
+                    pio.common.make_pio_pin(p.PIN_8),
+                    pio.common.make_pio_pin(p.PIN_9),
+                    pio.common.make_pio_pin(p.PIN_10),
+                    pio.common.make_pio_pin(p.PIN_11),
+                    pio.common.make_pio_pin(p.PIN_12),
+                    pio.common.make_pio_pin(p.PIN_13),
+                    pio.common.make_pio_pin(p.PIN_14),
+                    pio.common.make_pio_pin(p.PIN_15),
+                    pio.common.make_pio_pin(p.PIN_16),
+                    pio.common.make_pio_pin(p.PIN_17),
+                    pio.common.make_pio_pin(p.PIN_18),
+                    pio.common.make_pio_pin(p.PIN_19),
+                    pio.common.make_pio_pin(p.PIN_20),
+                    pio.common.make_pio_pin(p.PIN_21),
+                ];
+                SensorPins {
+                    pio,
+                    pins,
+                    dma: p.DMA_CH0,
+                }
+            },
+            #[cfg(feature = "mpr121-sensors")]
+            mpr121_i2c: embassy_rp::i2c::I2c::new_async(
+                p.I2C1,
+                p.PIN_3,
+                p.PIN_2,
+                Mpr121Irqs,
+                Default::default(),
+            ),
+            #[cfg(feature = "mpr121-sensors")]
+            mpr121_irq: Input::new(p.PIN_22, Pull::Up),
             adc: p.ADC,
             adc_pins: (p.PIN_28, p.PIN_29),
         },
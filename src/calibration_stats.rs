@@ -0,0 +1,218 @@
+// Statistics of the discharge times seen during calibration, separating
+// the untouched and touched readings of a pin
+
+use defmt::Format;
+use embassy_time::Duration;
+
+use crate::config::*;
+use crate::math::isqrt;
+
+/// Readings of one pin belonging together
+#[derive(Clone, Copy, Format)]
+pub struct Cluster {
+    pub count: u32,
+    pub mean: Duration,
+    pub sigma: Duration,
+}
+
+#[derive(Clone, Copy, Format)]
+pub struct Clusters {
+    pub idle: Cluster,
+    // None when the pin was not touched (enough)
+    pub touched: Option<Cluster>,
+    // boundary between the clusters
+    pub split: Duration,
+}
+
+/// Histogram of the discharge times of one pin
+#[derive(Clone, Copy)]
+pub struct Histogram {
+    bins: [u16; CALIBRATION_HISTOGRAM_BINS],
+    count: u32,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            bins: [0; CALIBRATION_HISTOGRAM_BINS],
+            count: 0,
+        }
+    }
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+    /// Add a reading, `None` (not discharged at all) counts as the longest time
+    pub fn add(&mut self, time: Option<Duration>) {
+        let bin = match time {
+            Some(t) => (t.as_ticks() / CALIBRATION_HISTOGRAM_BIN.as_ticks()) as usize,
+            None => CALIBRATION_HISTOGRAM_BINS,
+        }
+        .min(CALIBRATION_HISTOGRAM_BINS - 1);
+        if self.bins[bin] < u16::MAX {
+            self.bins[bin] += 1;
+            self.count += 1;
+        }
+    }
+    fn cluster(&self, bins: core::ops::Range<usize>) -> Option<Cluster> {
+        let start = bins.start;
+        let mut count = 0u64;
+        // bin centers in half bins
+        let mut sum = 0u64;
+        let mut sum_sq = 0u64;
+        for (i, &n) in self.bins[bins].iter().enumerate() {
+            let x = 2 * (start + i) as u64 + 1;
+            count += n as u64;
+            sum += n as u64 * x;
+            sum_sq += n as u64 * x * x;
+        }
+        if count == 0 {
+            return None;
+        }
+        let width = CALIBRATION_HISTOGRAM_BIN.as_ticks();
+        let mean = sum * width / (2 * count);
+        // spread of the bin centers plus the spread within a bin (1/12 bin squared)
+        let spread = (sum_sq * count - sum * sum) as u128;
+        let variance = (spread * (width * width) as u128 / (4 * (count * count) as u128)) as u64
+            + width * width / 12;
+        Some(Cluster {
+            count: count as u32,
+            mean: Duration::from_ticks(mean),
+            sigma: Duration::from_ticks(isqrt(variance.min(u32::MAX as u64) as u32) as u64),
+        })
+    }
+    /// Split the readings to the untouched and touched clusters where the
+    /// variance between them is the highest (Otsu's method).
+    ///
+    /// Clusters smaller than `CALIBRATION_MIN_CLUSTER` are not considered,
+    /// so a few glitches do not count as touches. `None` without any readings.
+    pub fn analyze(&self) -> Option<Clusters> {
+        let total = self.count as u64;
+        let total_sum: u64 = self
+            .bins
+            .iter()
+            .enumerate()
+            .map(|(i, &n)| i as u64 * n as u64)
+            .sum();
+
+        let mut best: Option<(u128, usize)> = None;
+        let mut w0 = 0u64;
+        let mut sum0 = 0u64;
+        for k in 1..CALIBRATION_HISTOGRAM_BINS {
+            w0 += self.bins[k - 1] as u64;
+            sum0 += (k - 1) as u64 * self.bins[k - 1] as u64;
+            let w1 = total - w0;
+            if w0 < CALIBRATION_MIN_CLUSTER as u64 || w1 < CALIBRATION_MIN_CLUSTER as u64 {
+                continue;
+            }
+            // (mu1 - mu0) * w0 * w1, the between-class variance is its square / (w0 * w1)
+            let diff = ((total_sum - sum0) * w0).abs_diff(sum0 * w1) as u128;
+            let between = diff * diff / (w0 * w1) as u128;
+            match best {
+                Some((b, _)) if b >= between => (),
+                _ => best = Some((between, k)),
+            }
+        }
+
+        let bin_time =
+            |k: usize| Duration::from_ticks(k as u64 * CALIBRATION_HISTOGRAM_BIN.as_ticks());
+        if let Some((_, k)) = best {
+            let idle = self.cluster(0..k)?;
+            let touched = self.cluster(k..CALIBRATION_HISTOGRAM_BINS)?;
+            // otherwise it is just the noise split in two
+            if touched.mean >= idle.mean + MIN_MARGIN_REQUIRED {
+                return Some(Clusters {
+                    idle,
+                    touched: Some(touched),
+                    split: bin_time(k),
+                });
+            }
+        }
+        Some(Clusters {
+            idle: self.cluster(0..CALIBRATION_HISTOGRAM_BINS)?,
+            touched: None,
+            split: bin_time(CALIBRATION_HISTOGRAM_BINS),
+        })
+    }
+}
+
+/// Quality of a sensor (permille) from how far touches get from the
+/// untouched readings (`separation`) compared to the noise (`spread`)
+pub fn quality(separation: Duration, spread: Duration) -> u16 {
+    let separation = separation.as_ticks();
+    let spread = spread.as_ticks() * CALIBRATION_QUALITY_SPREADS;
+    if separation == 0 {
+        return 0;
+    }
+    (separation * 1000 / (separation + spread)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(readings: &[(u64, usize)]) -> Histogram {
+        let mut histogram = Histogram::new();
+        for &(micros, count) in readings {
+            for _ in 0..count {
+                histogram.add(Some(Duration::from_micros(micros)));
+            }
+        }
+        histogram
+    }
+
+    #[test]
+    fn split_idle_and_touched() {
+        let clusters = histogram(&[(90, 20), (110, 20), (950, 5), (1050, 5)])
+            .analyze()
+            .unwrap();
+        let touched = clusters.touched.unwrap();
+        assert_eq!(clusters.idle.count, 40);
+        assert_eq!(touched.count, 10);
+        assert!(clusters.split > Duration::from_micros(110));
+        assert!(clusters.split <= Duration::from_micros(950));
+        assert!(clusters.idle.mean.as_micros().abs_diff(100) <= 10);
+        assert!(touched.mean.as_micros().abs_diff(1000) <= 10);
+        assert!(clusters.idle.sigma < touched.sigma);
+    }
+
+    #[test]
+    fn glitches_are_not_touches() {
+        let mut histogram = histogram(&[(100, 50)]);
+        for _ in 0..CALIBRATION_MIN_CLUSTER - 1 {
+            histogram.add(None);
+        }
+        let clusters = histogram.analyze().unwrap();
+        assert!(clusters.touched.is_none());
+        assert_eq!(clusters.idle.count, 50 + CALIBRATION_MIN_CLUSTER - 1);
+    }
+
+    #[test]
+    fn noise_is_not_split() {
+        let clusters = histogram(&[(100, 20), (120, 20)]).analyze().unwrap();
+        assert!(clusters.touched.is_none());
+        assert_eq!(clusters.idle.count, 40);
+    }
+
+    #[test]
+    fn empty() {
+        assert!(Histogram::new().analyze().is_none());
+        let mut histogram = histogram(&[(100, 10)]);
+        histogram.reset();
+        assert!(histogram.analyze().is_none());
+    }
+
+    #[test]
+    fn quality_from_separation_and_spread() {
+        let us = Duration::from_micros;
+        assert_eq!(quality(us(0), us(10)), 0);
+        assert_eq!(quality(us(100), us(0)), 1000);
+        assert_eq!(quality(us(CALIBRATION_QUALITY_SPREADS * 10), us(10)), 500);
+        assert!(quality(us(100), us(10)) < quality(us(200), us(10)));
+    }
+}
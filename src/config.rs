@@ -1,13 +1,260 @@
 use embassy_time::Duration;
 
+use crate::accessory::Accessory;
+use crate::material::MaterialBinding;
+use crate::midi::{Channel, U7};
+use crate::slider::Slider;
+use crate::touch_sensors::ScanGroup;
+
 // constants used throughout the code
 
 // sensors
 pub const NUM_SENSORS: usize = 16;
-pub const SENSOR_SAMPLES: usize = 10;
+// samples taken in a single scan
+pub const SENSOR_SAMPLES: usize = 2;
 pub const CALIBRATION_STEP_TIME: Duration = Duration::from_micros(5000);
 pub const MIN_TIME_REQUIRED: Duration = Duration::from_micros(10);
 pub const MIN_MARGIN_REQUIRED: Duration = Duration::from_micros(100);
+// histogram of the discharge times collected during calibration
+pub const CALIBRATION_HISTOGRAM_BIN: Duration = Duration::from_micros(20);
+pub const CALIBRATION_HISTOGRAM_BINS: usize = 256;
+// fewer readings than this (glitches) do not make a cluster of touched or untouched readings
+pub const CALIBRATION_MIN_CLUSTER: u32 = 3;
+// sensor quality is 500 (permille) when the touched readings are this many times
+// the sum of the standard deviations away from the untouched ones
+pub const CALIBRATION_QUALITY_SPREADS: u64 = 8;
+// sensors below this quality are shown as poor during calibration
+pub const CALIBRATION_QUALITY_POOR: u16 = 500;
+
+// material classification by the idle discharge time (see material.rs)
+pub const MATERIAL_WIRE_MIN: Duration = Duration::from_micros(30);
+pub const MATERIAL_FRUIT_MIN: Duration = Duration::from_micros(80);
+pub const MATERIAL_LARGE_MIN: Duration = Duration::from_micros(400);
+// in the order of `material::MATERIALS`
+pub const MATERIAL_BINDINGS: [MaterialBinding; 5] = [
+    // nothing
+    MaterialBinding {
+        channel: None,
+        program: None,
+        notes: None,
+    },
+    // wire
+    MaterialBinding {
+        channel: None,
+        program: None,
+        notes: None,
+    },
+    // small fruit
+    MaterialBinding {
+        channel: None,
+        program: None,
+        notes: None,
+    },
+    // large body
+    MaterialBinding {
+        channel: None,
+        program: None,
+        notes: None,
+    },
+    // short
+    MaterialBinding {
+        channel: None,
+        program: None,
+        notes: None,
+    },
+];
+
+// touch detection
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum TouchFilterKind {
+    // touched when at least `n` of the last `m` (up to 32) samples were 'on',
+    // released when at least `n` of them were 'off'
+    Majority { n: u8, m: u8 },
+    // counter going up on 'on' samples, down on 'off' samples,
+    // touched when it reaches `max`, released when it reaches 0
+    Integrator { max: u8 },
+}
+
+pub const TOUCH_FILTER: TouchFilterKind = TouchFilterKind::Integrator { max: 3 };
+// distance between the touch and release thresholds, in permile of the threshold window
+pub const TOUCH_HYSTERESIS: u32 = 150;
+
+// scanning the sensors in groups, the pins not scanned are driven low as guards,
+// less coupling and supply noise for more time per scan
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum ScanStrategy {
+    // all the pins at once
+    All,
+    // even pins, then odd pins
+    Interleaved,
+    // each pin in exactly one group, checked when building
+    Groups(&'static [ScanGroup]),
+}
+
+pub const SCAN_STRATEGY: ScanStrategy = ScanStrategy::All;
+// pause before each group of the interleaved scan
+pub const SCAN_GROUP_SETTLE: Duration = Duration::from_micros(20);
+
+// timing of the samples, against beating with mains hum (stage lighting...)
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum SampleTiming {
+    // as fast as possible, no interference detection
+    Fixed,
+    // random pause (up to HUM_DITHER_MAX) before each sample
+    Dithered,
+    // each sample at the same phase of the detected interference, dithered until
+    // some is detected; a sample may wait up to a period (20 ms at 50 Hz), one in
+    // HUM_DETECTION_INTERVAL at a random phase to keep detecting it
+    MainsSync,
+}
+
+pub const SAMPLE_TIMING: SampleTiming = SampleTiming::Dithered;
+pub const HUM_DITHER_MAX: Duration = Duration::from_micros(1000);
+// interference detection in the idle discharge times, the samples it uses are
+// always measured in full, as in analog mode
+pub const HUM_FREQUENCIES: [u32; 4] = [50, 60, 100, 120];
+// a detection window lasts at least HUM_WINDOW and HUM_MIN_SAMPLES samples
+pub const HUM_WINDOW: Duration = Duration::from_secs(1);
+pub const HUM_MIN_SAMPLES: u32 = 50;
+pub const HUM_DETECTION_INTERVAL: u32 = 4;
+// share (permille) of the reading variance an interference must explain to be reported
+pub const HUM_MIN_POWER: u32 = 300;
+pub const HUM_MIN_AMPLITUDE: Duration = Duration::from_micros(2);
+
+// runtime sensor fault detection
+// a sensor touched without a release for this long is stuck, longer than any
+// drone is held
+pub const FAULT_STUCK_ON_TIME: Duration = Duration::from_secs(60);
+pub const FAULT_TOGGLE_WINDOW: Duration = Duration::from_secs(1);
+// more touches and releases than this within FAULT_TOGGLE_WINDOW is not playing
+pub const FAULT_MAX_TOGGLES: u32 = 30;
+// a faulty sensor is enabled again after behaving well for this long
+pub const FAULT_RECOVERY_TIME: Duration = Duration::from_secs(3);
+
+// crosstalk suppression
+pub const CROSSTALK_SUPPRESSION: bool = true;
+// only scans where the strongest pin rose at least this much are used for learning
+pub const CROSSTALK_MIN_RISE: Duration = Duration::from_micros(100);
+// coupling (permile) below this is ignored
+pub const CROSSTALK_MIN_COUPLING: u16 = 150;
+// a touched pin must rise this much (percent) more than its neighbour to suppress it
+pub const CROSSTALK_DOMINANCE: u32 = 200;
+
+// PIO sensor backend (the 'pio-sensors' feature)
+// pin samples per second, the time resolution
+pub const PIO_SENSOR_SAMPLE_RATE: u32 = 2_000_000;
+// longest discharge time measured, no scan or calibration step asks for more
+pub const PIO_SENSOR_MAX_TIME: Duration = CALIBRATION_STEP_TIME;
+// each 32-bit word holds two samples of the 16 pins, enough for PIO_SENSOR_MAX_TIME
+pub const PIO_SENSOR_BUF_WORDS: usize =
+    ((PIO_SENSOR_MAX_TIME.as_micros() * PIO_SENSOR_SAMPLE_RATE as u64 / 1_000_000) as usize + 1)
+        .div_ceil(2);
+
+// MPR121 capacitive sensor backend (the 'mpr121-sensors' feature)
+pub const MPR121_ADDRESS: u8 = 0x5A;
+// status and levels are read at least this often, even without an interrupt
+pub const MPR121_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// touch threshold range (counts below the baseline) the sensitivity maps to
+pub const MPR121_TOUCH_THRESHOLD_MIN: u8 = 4;
+pub const MPR121_TOUCH_THRESHOLD_MAX: u8 = 40;
+// the touch threshold follows the sensitivity only when it moves by more than this,
+// every change stops the electrodes for a moment
+pub const MPR121_THRESHOLD_DEAD_BAND: u8 = 1;
+// release threshold, in percent of the touch threshold
+pub const MPR121_RELEASE_PERCENT: u8 = 60;
+// distance from the baseline (counts) reported as level 1000
+pub const MPR121_FULL_LEVEL: u32 = 120;
+
+// automatic calibration at power-up, from idle readings
+pub const AUTO_CALIBRATION: bool = true;
+pub const AUTO_CALIBRATION_TIME: Duration = Duration::from_millis(2000);
+pub const AUTO_CALIBRATION_INTERVAL: Duration = Duration::from_millis(10);
+// threshold distance from the mean idle discharge time, in standard deviations
+pub const AUTO_CALIBRATION_SIGMAS: u64 = 6;
+// minimum threshold distance from the longest idle discharge time
+pub const AUTO_CALIBRATION_MARGIN: Duration = Duration::from_micros(50);
+
+// baseline drift compensation
+pub const BASELINE_TRACKING: bool = true;
+// how often to measure idle discharge times when not measuring them anyway
+pub const BASELINE_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+// exponential filter factor, the higher, the slower the baseline follows
+pub const BASELINE_FILTER_DIV: i64 = 64;
+// samples above this level (permile of the threshold window) are not considered untouched
+pub const BASELINE_MAX_LEVEL: u32 = 250;
+// maximum baseline shift, in permile of the calibrated threshold window
+pub const BASELINE_MAX_DRIFT: u32 = 500;
+
+// velocity
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum VelocityCurve {
+    // always the same velocity, no analog measurement needed
+    Fixed(U7),
+    Linear,
+    // more velocity for gentle touches
+    Soft,
+    // more difference between gentle touches and slaps
+    Hard,
+}
+
+pub const VELOCITY_CURVE: VelocityCurve = VelocityCurve::Linear;
+// weights (sum: 100) of how fast and how far past the threshold the level moved on touch
+pub const VELOCITY_SPEED_WEIGHT: u32 = 50;
+pub const VELOCITY_DEPTH_WEIGHT: u32 = 100 - VELOCITY_SPEED_WEIGHT;
+
+// polyphonic aftertouch
+pub const AFTERTOUCH_ENABLED: bool = false;
+// minimum time between two pressure messages for the same key
+pub const AFTERTOUCH_INTERVAL: Duration = Duration::from_millis(20);
+// pressure changes smaller than this (in MIDI units) are not sent
+pub const AFTERTOUCH_DEAD_BAND: u8 = 2;
+
+// touch events (see touch_events.rs), the queue holds at least the events of a single scan
+pub const TOUCH_EVENT_QUEUE_SIZE: usize = 2 * NUM_SENSORS;
+pub const TOUCH_EVENT_SUBSCRIBERS: usize = 4;
+pub const TOUCH_EVENT_PUBLISHERS: usize = 1;
+
+// proximity (theremin-like) control, by approaching a sensor without touching it
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum ProximityMode {
+    // bend up, the closer the more
+    PitchBend,
+    // controller number, 0 far .. 127 near
+    Control(U7),
+    // notes in the range (absolute MIDI numbers) played while a hand is near
+    Note { low: U7, high: U7 },
+}
+
+// sensors used as proximity controllers instead of keys
+pub const PROXIMITY_SENSORS: [Option<ProximityMode>; NUM_SENSORS] = [None; NUM_SENSORS];
+// level range (permile of the calibrated window) mapped to the controller range,
+// the window starts at the idle level and is twice as wide as the distance to the
+// split between the idle and touched readings found by the calibration
+pub const PROXIMITY_LEVEL_MIN: u32 = 50;
+pub const PROXIMITY_LEVEL_MAX: u32 = 800;
+// exponential filter factor, the higher, the smoother and slower the control
+pub const PROXIMITY_FILTER_DIV: u32 = 8;
+// minimum time between two controller messages from the same sensor
+pub const PROXIMITY_INTERVAL: Duration = Duration::from_millis(10);
+pub const PROXIMITY_NOTE_VELOCITY: U7 = U7::saturating(100);
+
+// sliders, rows of adjacent sensors read as a continuous control
+pub const MAX_SLIDERS: usize = 2;
+pub const SLIDERS: [Option<Slider>; MAX_SLIDERS] = [None; MAX_SLIDERS];
+// the strongest pad must reach this level (permille) for a touch
+pub const SLIDER_MIN_LEVEL: u32 = 400;
+// levels below this do not pull the interpolated position
+pub const SLIDER_LEVEL_FLOOR: u32 = 100;
+// exponential filter factor of the position
+pub const SLIDER_FILTER_DIV: u32 = 4;
+// minimum time between two updates of the same slider
+pub const SLIDER_INTERVAL: Duration = Duration::from_millis(10);
+pub const SLIDER_NOTE_VELOCITY: U7 = U7::saturating(100);
 
 // leds
 pub const NUM_LEDS: usize = 19;
@@ -35,6 +282,7 @@ pub enum PianoKey {
 // must be still valid (just brighter) when multiplied by 4
 pub const COL_CAL_NA: u32 = 0x020100;
 pub const COL_CAL_OK: u32 = 0x000200;
+pub const COL_CAL_POOR: u32 = 0x010200;
 pub const COL_CAL_BAD: u32 = 0x040000;
 
 pub const SENSOR_TO_LED: [Option<usize>; NUM_SENSORS] = [
@@ -98,6 +346,84 @@ pub const SENSOR_TO_PIANO_KEY: [PianoKey; NUM_SENSORS] = [
     PianoKey::White, // D
 ];
 
+// accessories, identified by the resistance to ground on the last sensor connector pin
+pub const ACCESSORY_ADC_INPUT: usize = 1;
+pub const ACCESSORY_DEBOUNCE: Duration = Duration::from_millis(500);
+
+pub static ACCESSORIES: [Accessory; 3] = [
+    // default, also when nothing is connected (no ID resistor)
+    Accessory {
+        name: "fruit octave",
+        adc_min: 900,
+        adc_max: 1000,
+        root_note: ROOT_NOTE,
+        notes: SENSOR_TO_NOTE,
+        piano_keys: SENSOR_TO_PIANO_KEY,
+        leds: SENSOR_TO_LED,
+        sensitivity_min: 0,
+        sensitivity_max: 1000,
+    },
+    Accessory {
+        name: "drum pad mat",
+        adc_min: 150,
+        adc_max: 350,
+        root_note: 0,
+        // General MIDI percussion
+        notes: [
+            Some(35), // acoustic bass drum
+            Some(36), // bass drum
+            Some(37), // side stick
+            Some(38), // acoustic snare
+            Some(39), // hand clap
+            Some(40), // electric snare
+            Some(41), // low floor tom
+            Some(42), // closed hi-hat
+            Some(43), // high floor tom
+            Some(44), // pedal hi-hat
+            Some(45), // low tom
+            Some(46), // open hi-hat
+            Some(48), // hi-mid tom
+            Some(49), // crash cymbal
+            Some(50), // high tom
+            Some(51), // ride cymbal
+        ],
+        piano_keys: [PianoKey::White; NUM_SENSORS],
+        leds: SENSOR_TO_LED,
+        // the pads are big, they need less sensitivity
+        sensitivity_min: 100,
+        sensitivity_max: 600,
+    },
+    Accessory {
+        name: "two-octave strip",
+        adc_min: 400,
+        adc_max: 600,
+        root_note: 48,
+        // white keys only
+        notes: [
+            Some(0),  // C
+            Some(2),  // D
+            Some(4),  // E
+            Some(5),  // F
+            Some(7),  // G
+            Some(9),  // A
+            Some(11), // B
+            Some(12), // C
+            Some(14), // D
+            Some(16), // E
+            Some(17), // F
+            Some(19), // G
+            Some(21), // A
+            Some(23), // B
+            Some(24), // C
+            Some(26), // D
+        ],
+        piano_keys: [PianoKey::White; NUM_SENSORS],
+        leds: SENSOR_TO_LED,
+        sensitivity_min: 0,
+        sensitivity_max: 1000,
+    },
+];
+
 // button
 pub const DEBOUNCE_TIME: Duration = Duration::from_millis(2);
 
@@ -107,6 +433,28 @@ pub const ADC_INTERVAL: Duration = Duration::from_millis(10);
 // MIDI
 // let it hold note-offs for all keys and a few more messages
 pub const MIDI_CHANNEL_SIZE: usize = 20;
+// channel the keyboard plays on, 0 is MIDI channel 1
+pub const MIDI_OUT_CHANNEL: Channel = Channel::saturating(0);
+// longest SysEx handled, data bytes without F0 and F7
+pub const SYSEX_MAX_LEN: usize = 32;
+
+// how often note-offs an output had no room for are tried again
+pub const MIDI_RETRY_INTERVAL: Duration = Duration::from_millis(2);
+// how often the numbers of messages the outputs dropped are logged, when changed
+pub const MIDI_DROPPED_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+// USB MIDI
+// virtual cables (jacks) of the interface, the keyboard plays on the first one
+pub const USB_MIDI_CABLES: usize = 1;
 
 // serial MIDI
-pub const SERIAL_MIDI_BUF_LEN: usize = 128;
+// kept short, so the messages wait in the DIN queue where they can still be
+// reordered and merged, rather than in the UART buffer
+pub const SERIAL_MIDI_BUF_LEN: usize = 8;
+// messages waiting for the DIN output
+pub const DIN_QUEUE_SIZE: usize = 32;
+// note-offs without release velocity are sent as note-ons with 0 velocity,
+// sharing the running status with the note-ons
+pub const DIN_NOTE_OFF_AS_NOTE_ON: bool = true;
+// the status byte is repeated at least this often, for receivers connected meanwhile
+pub const DIN_STATUS_REFRESH: Duration = Duration::from_millis(1000);
@@ -0,0 +1,182 @@
+// Crosstalk between the sensors (e.g. neighbours in a long ribbon cable)
+
+use defmt::{write, Format, Formatter};
+
+use crate::config::*;
+
+pub struct Crosstalk {
+    // sums of ratios (permile) of the rise of a pin (column) to the rise of
+    // the dominating touched pin (row), and the number of such observations
+    sums: [[u32; NUM_SENSORS]; NUM_SENSORS],
+    counts: [u32; NUM_SENSORS],
+    coupling: [[u16; NUM_SENSORS]; NUM_SENSORS],
+}
+
+impl Default for Crosstalk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crosstalk {
+    pub fn new() -> Self {
+        Self {
+            sums: [[0; NUM_SENSORS]; NUM_SENSORS],
+            counts: [0; NUM_SENSORS],
+            coupling: [[0; NUM_SENSORS]; NUM_SENSORS],
+        }
+    }
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+    /// Learn from a single scan during calibration.
+    ///
+    /// `rises` are the discharge times above the idle ones, in ticks,
+    /// `None` for pins to ignore.
+    pub fn learn(&mut self, rises: &[Option<u64>; NUM_SENSORS]) {
+        let mut strongest: Option<(usize, u64)> = None;
+        for (i, rise) in rises.iter().enumerate() {
+            match (*rise, strongest) {
+                (Some(rise), Some((_, max))) if rise <= max => (),
+                (Some(rise), _) => strongest = Some((i, rise)),
+                (None, _) => (),
+            }
+        }
+        let (j, max_rise) = match strongest {
+            Some((j, max_rise)) if max_rise >= CROSSTALK_MIN_RISE.as_ticks() => (j, max_rise),
+            _ => return,
+        };
+        for (i, rise) in rises.iter().enumerate() {
+            if i == j {
+                continue;
+            }
+            if let Some(rise) = *rise {
+                self.sums[j][i] += (rise * 1000 / max_rise) as u32;
+            }
+        }
+        self.counts[j] += 1;
+        for i in 0..NUM_SENSORS {
+            self.coupling[j][i] = (self.sums[j][i] / self.counts[j]).min(1000) as u16;
+        }
+    }
+    /// Coupling matrix: how much (permile) touching a pin (row) raises the
+    /// discharge time of another pin (column)
+    pub fn coupling(&self) -> &[[u16; NUM_SENSORS]; NUM_SENSORS] {
+        &self.coupling
+    }
+    /// Any coupling worth suppressing has been learned
+    pub fn is_significant(&self) -> bool {
+        self.coupling
+            .iter()
+            .flatten()
+            .any(|c| *c >= CROSSTALK_MIN_COUPLING)
+    }
+    /// Turn off pins whose rise is explained by crosstalk from a clearly
+    /// dominating touched pin.
+    pub fn suppress(&self, rises: &[u64; NUM_SENSORS], on: &mut [bool; NUM_SENSORS]) {
+        let touched = *on;
+        for i in 0..NUM_SENSORS {
+            if !touched[i] {
+                continue;
+            }
+            for j in 0..NUM_SENSORS {
+                let coupling = self.coupling[j][i] as u64;
+                if i == j || !touched[j] || coupling < CROSSTALK_MIN_COUPLING as u64 {
+                    continue;
+                }
+                let dominates = rises[j] * 100 >= rises[i] * CROSSTALK_DOMINANCE as u64;
+                // expected ghost rise, with some margin
+                let explained = rises[i] * 1000 <= rises[j] * coupling * 3 / 2;
+                if dominates && explained {
+                    on[i] = false;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Format for Crosstalk {
+    fn format(&self, f: Formatter) {
+        let mut first = true;
+        for (j, row) in self.coupling.iter().enumerate() {
+            for (i, coupling) in row.iter().enumerate() {
+                if *coupling < CROSSTALK_MIN_COUPLING {
+                    continue;
+                }
+                if !first {
+                    write!(f, ", ");
+                } else {
+                    first = false;
+                }
+                write!(f, "{}->{}: {}", j, i, coupling);
+            }
+        }
+        if first {
+            write!(f, "none");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Crosstalk learned from touching pin 0, raising pin 1 by 30 %
+    fn learned() -> (Crosstalk, u64) {
+        let rise = CROSSTALK_MIN_RISE.as_ticks() * 10;
+        let mut rises = [Some(0); NUM_SENSORS];
+        rises[0] = Some(rise);
+        rises[1] = Some(rise * 3 / 10);
+        let mut crosstalk = Crosstalk::new();
+        crosstalk.learn(&rises);
+        (crosstalk, rise)
+    }
+
+    #[test]
+    fn learn_the_coupling() {
+        let (crosstalk, _) = learned();
+        assert_eq!(crosstalk.coupling()[0][1], 300);
+        assert_eq!(crosstalk.coupling()[0][2], 0);
+        assert_eq!(crosstalk.coupling()[1][0], 0);
+        assert!(crosstalk.is_significant());
+    }
+
+    #[test]
+    fn small_rises_are_not_learned() {
+        let mut rises = [None; NUM_SENSORS];
+        rises[0] = Some(CROSSTALK_MIN_RISE.as_ticks() - 1);
+        rises[1] = Some(CROSSTALK_MIN_RISE.as_ticks() / 2);
+        let mut crosstalk = Crosstalk::new();
+        crosstalk.learn(&rises);
+        assert!(!crosstalk.is_significant());
+    }
+
+    #[test]
+    fn suppress_the_ghost_touch() {
+        let (crosstalk, rise) = learned();
+        let mut rises = [0; NUM_SENSORS];
+        rises[0] = rise;
+        rises[1] = rise * 3 / 10;
+        let mut on = [false; NUM_SENSORS];
+        on[0] = true;
+        on[1] = true;
+        crosstalk.suppress(&rises, &mut on);
+        assert!(on[0]);
+        assert!(!on[1]);
+    }
+
+    #[test]
+    fn keep_a_real_touch() {
+        let (crosstalk, rise) = learned();
+        let mut rises = [0; NUM_SENSORS];
+        rises[0] = rise;
+        rises[1] = rise * 8 / 10;
+        let mut on = [false; NUM_SENSORS];
+        on[0] = true;
+        on[1] = true;
+        crosstalk.suppress(&rises, &mut on);
+        assert!(on[0]);
+        assert!(on[1]);
+    }
+}
@@ -0,0 +1,457 @@
+// DIN MIDI output scheduling: at 31250 baud every byte takes 320 us, so the
+// messages waiting are reordered and merged, and sent with running status
+
+use embassy_time::Instant;
+
+use crate::config::*;
+use crate::midi::{MidiMsg, U7};
+
+// note-off, or a note-on doing the same
+fn is_note_off(msg: &MidiMsg) -> bool {
+    match msg {
+        MidiMsg::NoteOff { .. } => true,
+        MidiMsg::NoteOn { velocity, .. } => *velocity == U7::MIN,
+        _ => false,
+    }
+}
+
+// `old` is a note message for the same key as `msg`
+fn same_key(msg: &MidiMsg, old: &MidiMsg) -> bool {
+    match (msg, old) {
+        (
+            MidiMsg::NoteOn { channel, note, .. } | MidiMsg::NoteOff { channel, note, .. },
+            MidiMsg::NoteOn {
+                channel: old_channel,
+                note: old_note,
+                ..
+            }
+            | MidiMsg::NoteOff {
+                channel: old_channel,
+                note: old_note,
+                ..
+            },
+        ) => channel == old_channel && note == old_note,
+        _ => false,
+    }
+}
+
+// `msg` is a new value of the controller `old` sets
+fn supersedes(msg: &MidiMsg, old: &MidiMsg) -> bool {
+    match (msg, old) {
+        (
+            MidiMsg::ControlChange {
+                channel, control, ..
+            },
+            MidiMsg::ControlChange {
+                channel: old_channel,
+                control: old_control,
+                ..
+            },
+        ) => channel == old_channel && control == old_control,
+        (
+            MidiMsg::PolyPressure { channel, note, .. },
+            MidiMsg::PolyPressure {
+                channel: old_channel,
+                note: old_note,
+                ..
+            },
+        ) => channel == old_channel && note == old_note,
+        (
+            MidiMsg::ChannelPressure { channel, .. },
+            MidiMsg::ChannelPressure {
+                channel: old_channel,
+                ..
+            },
+        )
+        | (
+            MidiMsg::PitchBend { channel, .. },
+            MidiMsg::PitchBend {
+                channel: old_channel,
+                ..
+            },
+        ) => channel == old_channel,
+        _ => false,
+    }
+}
+
+/// Messages waiting for the DIN output.
+///
+/// Real time messages go first, then note-offs (never before a note-on of
+/// the same key), then the rest in order. A controller value waiting is
+/// dropped when a new one for the same controller comes.
+pub struct DinQueue {
+    msgs: [Option<MidiMsg>; DIN_QUEUE_SIZE],
+    len: usize,
+}
+
+impl Default for DinQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DinQueue {
+    pub fn new() -> Self {
+        Self {
+            msgs: [None; DIN_QUEUE_SIZE],
+            len: 0,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn is_full(&self) -> bool {
+        self.len == DIN_QUEUE_SIZE
+    }
+    fn get(&self, i: usize) -> &MidiMsg {
+        self.msgs[i].as_ref().unwrap()
+    }
+    fn remove(&mut self, i: usize) -> MidiMsg {
+        let msg = self.msgs[i].take().unwrap();
+        self.msgs[i..self.len].rotate_left(1);
+        self.len -= 1;
+        msg
+    }
+    /// Queue a message, returns false when it was dropped for lack of room
+    pub fn push(&mut self, msg: MidiMsg) -> bool {
+        // the new value goes to the end, not to the place of the old one, so
+        // it is not sent before the messages queued after the old one
+        if let Some(i) = (0..self.len).find(|&i| supersedes(&msg, self.get(i))) {
+            self.remove(i);
+        }
+        if self.is_full() {
+            return false;
+        }
+        self.msgs[self.len] = Some(msg);
+        self.len += 1;
+        true
+    }
+    /// The message to send next
+    pub fn pop(&mut self) -> Option<MidiMsg> {
+        if self.is_empty() {
+            return None;
+        }
+        let i = (0..self.len)
+            .find(|&i| self.get(i).is_realtime())
+            .or_else(|| {
+                (0..self.len).find(|&i| {
+                    let msg = self.get(i);
+                    is_note_off(msg) && !(0..i).any(|j| same_key(msg, self.get(j)))
+                })
+            })
+            .unwrap_or(0);
+        Some(self.remove(i))
+    }
+}
+
+/// Serializer leaving out the status byte when it is the same as the one of
+/// the previous channel message (running status)
+pub struct RunningStatusEncoder {
+    status: Option<u8>,
+    status_sent_at: Instant,
+}
+
+impl Default for RunningStatusEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunningStatusEncoder {
+    pub fn new() -> Self {
+        Self {
+            status: None,
+            status_sent_at: Instant::MIN,
+        }
+    }
+    /// Send the status byte with the next message, e.g. after an output error
+    pub fn reset(&mut self) {
+        self.status = None;
+    }
+    /// Write the bytes of `msg` to `buf` (`MidiMsg::MAX_LEN` long), returns
+    /// their number
+    pub fn encode(&mut self, msg: &MidiMsg, now: Instant, buf: &mut [u8]) -> usize {
+        let msg = match *msg {
+            MidiMsg::NoteOff {
+                channel,
+                note,
+                velocity,
+            } if DIN_NOTE_OFF_AS_NOTE_ON && velocity == U7::MIN => MidiMsg::NoteOn {
+                channel,
+                note,
+                velocity,
+            },
+            msg => msg,
+        };
+        let len = msg.serialize(buf);
+        if len == 0 || msg.is_realtime() {
+            // real time bytes do not interrupt the running status
+            return len;
+        }
+        let status = buf[0];
+        if status >= 0xF0 {
+            // system common and SysEx cancel it
+            self.status = None;
+            return len;
+        }
+        if self.status == Some(status) && now < self.status_sent_at + DIN_STATUS_REFRESH {
+            buf.copy_within(1..len, 0);
+            return len - 1;
+        }
+        self.status = Some(status);
+        self.status_sent_at = now;
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(bytes: &[u8]) -> MidiMsg {
+        MidiMsg::from_bytes(bytes).unwrap()
+    }
+
+    /// Bytes of the queued messages, in the order sent
+    fn drain(queue: &mut DinQueue) -> Vec<Vec<u8>> {
+        let mut sent = Vec::new();
+        while let Some(msg) = queue.pop() {
+            let mut buf = [0; MidiMsg::MAX_LEN];
+            let len = msg.serialize(&mut buf);
+            sent.push(buf[..len].to_vec());
+        }
+        sent
+    }
+
+    fn queue(msgs: &[&[u8]]) -> DinQueue {
+        let mut queue = DinQueue::new();
+        for bytes in msgs {
+            assert!(queue.push(msg(bytes)));
+        }
+        queue
+    }
+
+    #[test]
+    fn realtime_first_then_note_offs() {
+        let mut queue = queue(&[
+            &[0xB0, 1, 10],
+            &[0x90, 61, 100],
+            &[0x80, 59, 0],
+            &[0xF8],
+            &[0x90, 58, 0],
+            &[0xC0, 5],
+            &[0xFA],
+        ]);
+        assert_eq!(
+            drain(&mut queue),
+            [
+                vec![0xF8],
+                vec![0xFA],
+                vec![0x80, 59, 0],
+                vec![0x90, 58, 0],
+                vec![0xB0, 1, 10],
+                vec![0x90, 61, 100],
+                vec![0xC0, 5]
+            ]
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn note_off_does_not_overtake_its_note_on() {
+        let mut queue = queue(&[
+            &[0xB0, 1, 10],
+            &[0x90, 60, 100],
+            &[0x80, 60, 0],
+            &[0x90, 62, 100],
+            &[0x90, 62, 0],
+            &[0x81, 60, 0],
+        ]);
+        // the note-off of 60 on channel 2 has no note-on before it
+        assert_eq!(
+            drain(&mut queue),
+            [
+                vec![0x81, 60, 0],
+                vec![0xB0, 1, 10],
+                vec![0x90, 60, 100],
+                vec![0x80, 60, 0],
+                vec![0x90, 62, 100],
+                vec![0x90, 62, 0]
+            ]
+        );
+    }
+
+    #[test]
+    fn newer_controller_values_replace_the_waiting_ones() {
+        let mut queue = queue(&[
+            &[0xB0, 1, 10],
+            &[0xE0, 0, 0x40],
+            &[0xD0, 5],
+            &[0xA0, 60, 5],
+            &[0x90, 60, 100],
+            &[0xB0, 1, 20],
+            &[0xB0, 2, 20],
+            &[0xB1, 1, 20],
+            &[0xE0, 1, 0x40],
+            &[0xD0, 6],
+            &[0xA0, 60, 6],
+            &[0xA0, 61, 6],
+        ]);
+        // each new value goes after the messages queued before it
+        assert_eq!(
+            drain(&mut queue),
+            [
+                vec![0x90, 60, 100],
+                vec![0xB0, 1, 20],
+                vec![0xB0, 2, 20],
+                vec![0xB1, 1, 20],
+                vec![0xE0, 1, 0x40],
+                vec![0xD0, 6],
+                vec![0xA0, 60, 6],
+                vec![0xA0, 61, 6]
+            ]
+        );
+    }
+
+    #[test]
+    fn full_queue() {
+        let mut queue = DinQueue::new();
+        for note in 0..DIN_QUEUE_SIZE as u8 - 1 {
+            assert!(queue.push(msg(&[0x90, note, 100])));
+        }
+        assert!(queue.push(msg(&[0xB0, 1, 10])));
+        assert!(queue.is_full());
+        assert!(!queue.push(msg(&[0x90, 127, 100])));
+        // a new value takes the place of the old one
+        assert!(queue.push(msg(&[0xB0, 1, 20])));
+        let sent = drain(&mut queue);
+        assert_eq!(sent.len(), DIN_QUEUE_SIZE);
+        assert_eq!(sent.last().unwrap(), &[0xB0, 1, 20]);
+    }
+
+    /// Bytes sent for `msgs`, each with its time in ms
+    fn encode(encoder: &mut RunningStatusEncoder, msgs: &[(u64, &[u8])]) -> Vec<Vec<u8>> {
+        msgs.iter()
+            .map(|&(ms, bytes)| {
+                let mut buf = [0; MidiMsg::MAX_LEN];
+                let now = Instant::from_millis(ms);
+                let len = encoder.encode(&msg(bytes), now, &mut buf);
+                buf[..len].to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn running_status() {
+        let mut encoder = RunningStatusEncoder::new();
+        let sent = encode(
+            &mut encoder,
+            &[
+                (0, &[0x90, 60, 100]),
+                (0, &[0x90, 64, 100]),
+                (0, &[0x91, 64, 100]),
+                (0, &[0xB1, 1, 2]),
+                (0, &[0xB1, 1, 3]),
+                (0, &[0x81, 64, 64]),
+            ],
+        );
+        assert_eq!(
+            sent,
+            [
+                vec![0x90, 60, 100],
+                vec![64, 100],
+                vec![0x91, 64, 100],
+                vec![0xB1, 1, 2],
+                vec![1, 3],
+                vec![0x81, 64, 64]
+            ]
+        );
+        encoder.reset();
+        assert_eq!(encode(&mut encoder, &[(0, &[0x81, 64, 64])])[0][0], 0x81);
+    }
+
+    #[test]
+    fn note_off_as_note_on() {
+        let mut encoder = RunningStatusEncoder::new();
+        let sent = encode(
+            &mut encoder,
+            &[
+                (0, &[0x90, 60, 100]),
+                (0, &[0x90, 64, 100]),
+                (0, &[0x80, 60, 0]),
+                (0, &[0x80, 64, 0]),
+            ],
+        );
+        if DIN_NOTE_OFF_AS_NOTE_ON {
+            assert_eq!(sent[2..], [[60, 0], [64, 0]]);
+        } else {
+            assert_eq!(sent[2..], [vec![0x80, 60, 0], vec![64, 0]]);
+        }
+    }
+
+    #[test]
+    fn status_is_refreshed() {
+        let refresh = DIN_STATUS_REFRESH.as_millis();
+        let mut encoder = RunningStatusEncoder::new();
+        let sent = encode(
+            &mut encoder,
+            &[
+                (1000, &[0xB0, 1, 1]),
+                (1000 + refresh - 1, &[0xB0, 1, 2]),
+                (1000 + refresh, &[0xB0, 1, 3]),
+                (1000 + refresh + 1, &[0xB0, 1, 4]),
+            ],
+        );
+        assert_eq!(
+            sent,
+            [vec![0xB0, 1, 1], vec![1, 2], vec![0xB0, 1, 3], vec![1, 4]]
+        );
+    }
+
+    #[test]
+    fn realtime_keeps_running_status() {
+        let mut encoder = RunningStatusEncoder::new();
+        let sent = encode(
+            &mut encoder,
+            &[
+                (0, &[0x90, 60, 100]),
+                (0, &[0xF8]),
+                (0, &[0xFE]),
+                (0, &[0x90, 61, 100]),
+            ],
+        );
+        assert_eq!(
+            sent,
+            [vec![0x90, 60, 100], vec![0xF8], vec![0xFE], vec![61, 100]]
+        );
+    }
+
+    #[test]
+    fn system_messages_cancel_running_status() {
+        let cancelling: [&[u8]; 4] = [&[0xF0, 1, 2, 0xF7], &[0xF3, 1], &[0xF2, 1, 2], &[0xF6]];
+        for bytes in cancelling {
+            let mut encoder = RunningStatusEncoder::new();
+            let sent = encode(
+                &mut encoder,
+                &[(0, &[0x90, 60, 100]), (0, bytes), (0, &[0x90, 61, 100])],
+            );
+            assert_eq!(sent[1], bytes);
+            assert_eq!(sent[2], [0x90, 61, 100], "after {:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn no_room_no_change() {
+        let mut encoder = RunningStatusEncoder::new();
+        assert_eq!(
+            encoder.encode(&msg(&[0xF0, 1, 0xF7]), Instant::MIN, &mut [0; 2]),
+            0
+        );
+        encode(&mut encoder, &[(0, &[0x90, 60, 100])]);
+        assert_eq!(
+            encoder.encode(&msg(&[0xF3, 1]), Instant::MIN, &mut [0; 1]),
+            0
+        );
+        assert_eq!(encode(&mut encoder, &[(0, &[0x90, 61, 100])]), [[61, 100]]);
+    }
+}
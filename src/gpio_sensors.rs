@@ -0,0 +1,87 @@
+// GPIO polling discharge timing for the touch sensors
+
+use embassy_rp::gpio::{Flex, Pull};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::config::*;
+use crate::touch_sensors::DischargeTimer;
+
+/// Discharge timing by polling the GPIO pins
+pub struct GpioDischargeTimer<'a> {
+    pins: [Flex<'a>; NUM_SENSORS],
+}
+
+impl<'a> GpioDischargeTimer<'a> {
+    pub fn new(mut pins: [Flex<'a>; NUM_SENSORS]) -> Self {
+        for pin in &mut pins {
+            pin.set_pull(Pull::None);
+        }
+        Self { pins }
+    }
+    /// Charge the pins to scan, drive the others low, then release the
+    /// pins to scan and return the time they were released at
+    async fn charge(&mut self, pins: u32) -> Instant {
+        for (i, pin) in self.pins.iter_mut().enumerate() {
+            pin.set_as_output();
+            if pins & (1 << i) != 0 {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+
+        Timer::after(Duration::from_micros(1)).await;
+
+        let t0 = Instant::now();
+
+        for (i, pin) in self.pins.iter_mut().enumerate() {
+            if pins & (1 << i) != 0 {
+                pin.set_as_input();
+            }
+        }
+        t0
+    }
+}
+
+impl<'a> DischargeTimer for GpioDischargeTimer<'a> {
+    async fn measure_until(
+        &mut self,
+        limit: Duration,
+        pins: u32,
+    ) -> [Option<Duration>; NUM_SENSORS] {
+        let t0 = self.charge(pins).await;
+
+        let mut times = [None; NUM_SENSORS];
+        let mut num_done = 0;
+        let num_pins = (pins & ((1 << NUM_SENSORS) - 1)).count_ones();
+        loop {
+            Timer::after(Duration::from_micros(1)).await;
+            let t = Instant::elapsed(&t0);
+            for (i, (time, pin)) in times.iter_mut().zip(self.pins.iter()).enumerate() {
+                if time.is_none() && pins & (1 << i) != 0 && pin.is_low() {
+                    *time = Some(t);
+                    num_done += 1;
+                }
+            }
+            if num_done == num_pins || t >= limit {
+                break;
+            }
+        }
+        times
+    }
+    async fn sample(
+        &mut self,
+        thresholds: &[Duration; NUM_SENSORS],
+        order: &[usize; NUM_SENSORS],
+        pins: u32,
+    ) -> [bool; NUM_SENSORS] {
+        let t0 = self.charge(pins).await;
+
+        let mut on = [false; NUM_SENSORS];
+        for &i in order.iter().filter(|&&i| pins & (1 << i) != 0) {
+            Timer::at(t0 + thresholds[i]).await;
+            on[i] = self.pins[i].is_high();
+        }
+        on
+    }
+}
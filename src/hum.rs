@@ -0,0 +1,260 @@
+// Mains hum: detecting periodic interference in the idle discharge times
+// and scheduling the samples so it does not beat with the scanning
+
+use defmt::{debug, info, Format};
+use embassy_time::{Duration, Instant, Timer, TICK_HZ};
+
+use crate::config::*;
+use crate::math::{cos_permille, isqrt64, sin_permille};
+
+/// Interference found in the last detection window
+#[derive(Clone, Copy, Format, PartialEq)]
+pub struct HumReport {
+    // Hz, None when nothing stands out of the noise
+    pub frequency: Option<u32>,
+    // amplitude of the strongest candidate, in ticks
+    pub amplitude: u64,
+    // share (permille) of the variance of the readings explained by it
+    pub power: u32,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Accumulator {
+    // sums of x * cos, x * sin (permille), and of cos and sin alone
+    x_cos: i64,
+    x_sin: i64,
+    cos: i64,
+    sin: i64,
+}
+
+/// Fourier components of the readings at the candidate frequencies,
+/// the readings need not be taken at regular intervals.
+pub struct HumDetector {
+    acc: [Accumulator; HUM_FREQUENCIES.len()],
+    sum: i64,
+    sum_sq: i64,
+    count: i64,
+    window_start: Instant,
+    report: HumReport,
+}
+
+impl Default for HumDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HumDetector {
+    pub fn new() -> Self {
+        Self {
+            acc: [Accumulator::default(); HUM_FREQUENCIES.len()],
+            sum: 0,
+            sum_sq: 0,
+            count: 0,
+            window_start: Instant::now(),
+            report: HumReport {
+                frequency: None,
+                amplitude: 0,
+                power: 0,
+            },
+        }
+    }
+    pub fn report(&self) -> HumReport {
+        self.report
+    }
+    /// Add a reading (idle discharge time, in ticks) taken at `time`
+    pub fn add(&mut self, time: Instant, value: u64) {
+        let x = value as i64;
+        for (acc, frequency) in self.acc.iter_mut().zip(HUM_FREQUENCIES.iter()) {
+            let period = TICK_HZ / *frequency as u64;
+            let phase = ((time.as_ticks() % period) * 256 / period) as u32;
+            let (c, s) = (cos_permille(phase) as i64, sin_permille(phase) as i64);
+            acc.x_cos += x * c;
+            acc.x_sin += x * s;
+            acc.cos += c;
+            acc.sin += s;
+        }
+        self.sum += x;
+        self.sum_sq += x * x;
+        self.count += 1;
+        if time.saturating_duration_since(self.window_start) >= HUM_WINDOW
+            && self.count >= HUM_MIN_SAMPLES as i64
+        {
+            self.evaluate();
+            *self = Self {
+                report: self.report,
+                window_start: time,
+                ..Self::new()
+            };
+        }
+    }
+    fn evaluate(&mut self) {
+        let n = self.count;
+        let variance = ((n * self.sum_sq - self.sum * self.sum) / (n * n)).max(1) as u64;
+        let mut best = (0, 0u64);
+        for (acc, frequency) in self.acc.iter().zip(HUM_FREQUENCIES.iter()) {
+            // components of the readings without their mean, in 1/1000 ticks
+            let re = (2 * (n * acc.x_cos - self.sum * acc.cos) / (n * n)).unsigned_abs();
+            let im = (2 * (n * acc.x_sin - self.sum * acc.sin) / (n * n)).unsigned_abs();
+            let amplitude = isqrt64(re * re + im * im) / 1000;
+            if amplitude > best.1 {
+                best = (*frequency, amplitude);
+            }
+        }
+        let (frequency, amplitude) = best;
+        // a sine of amplitude A has the variance A^2 / 2
+        let power = (amplitude * amplitude * 1000 / (2 * variance)).min(1000) as u32;
+        let detected = power >= HUM_MIN_POWER && amplitude >= HUM_MIN_AMPLITUDE.as_ticks();
+        let report = HumReport {
+            frequency: if detected { Some(frequency) } else { None },
+            amplitude,
+            power,
+        };
+        debug!("Hum: {}", report);
+        if report.frequency != self.report.frequency {
+            info!("Interference: {} Hz, power {}", report.frequency, power);
+        }
+        self.report = report;
+    }
+}
+
+/// Timing of the samples (see `SampleTiming`)
+pub struct SampleScheduler {
+    timing: SampleTiming,
+    random: u32,
+    // samples since the last one taken at a random phase of the interference
+    locked: u32,
+}
+
+impl SampleScheduler {
+    pub fn new(timing: SampleTiming) -> Self {
+        Self {
+            timing,
+            random: 0x2545_F491,
+            locked: 0,
+        }
+    }
+    // xorshift, good enough for spreading the samples
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.random;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random = x;
+        x
+    }
+    async fn dither(&mut self) {
+        let max = HUM_DITHER_MAX.as_ticks() as u32;
+        if max > 0 {
+            let delay = self.next_random() % (max + 1);
+            Timer::after(Duration::from_ticks(delay as u64)).await;
+        }
+    }
+    /// Wait until the next sample is due, `hum` is the interference detected.
+    ///
+    /// Returns whether the sample is at a random phase of the interference, only
+    /// such samples may be used to detect it.
+    pub async fn wait(&mut self, hum: &HumReport) -> bool {
+        match (self.timing, hum.frequency) {
+            (SampleTiming::Fixed, _) => false,
+            (SampleTiming::Dithered, _) | (SampleTiming::MainsSync, None) => {
+                self.dither().await;
+                true
+            }
+            (SampleTiming::MainsSync, Some(frequency)) => {
+                let period = TICK_HZ / frequency as u64;
+                self.locked += 1;
+                if self.locked >= HUM_DETECTION_INTERVAL {
+                    // locked samples all see the same offset, spread this one over a period
+                    self.locked = 0;
+                    let delay = self.next_random() as u64 % period;
+                    Timer::after(Duration::from_ticks(delay)).await;
+                    return true;
+                }
+                // always at the same phase of the interference, so it is a constant offset
+                let now = Instant::now().as_ticks();
+                Timer::at(Instant::from_ticks((now / period + 1) * period)).await;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    /// Report after readings with a hum of `frequency` and `amplitude` ticks,
+    /// taken at irregular intervals
+    fn detect(frequency: u32, amplitude: f64) -> HumReport {
+        let mut detector = HumDetector::new();
+        let start = Instant::now();
+        let mut random = 12345u32;
+        let mut t = 0u64;
+        while t < 2 * HUM_WINDOW.as_ticks() {
+            random = random.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            t += TICK_HZ / 1000 + (random >> 16) as u64 % (TICK_HZ / 200);
+            let phase = t as f64 * frequency as f64 / TICK_HZ as f64;
+            let hum = amplitude * (2.0 * core::f64::consts::PI * phase).sin();
+            // some noise as well
+            let noise = ((random >> 8) % 3) as f64 - 1.0;
+            let value = (10 * TICK_HZ / 1000) as i64 + (hum + noise) as i64;
+            detector.add(start + Duration::from_ticks(t), value as u64);
+        }
+        detector.report()
+    }
+
+    fn amplitude() -> f64 {
+        (HUM_MIN_AMPLITUDE.as_ticks() * 10) as f64
+    }
+
+    #[test]
+    fn detect_50_hz() {
+        let report = detect(50, amplitude());
+        assert_eq!(report.frequency, Some(50));
+        assert!(report.amplitude.abs_diff(amplitude() as u64) <= amplitude() as u64 / 5);
+    }
+
+    #[test]
+    fn detect_60_hz() {
+        assert_eq!(detect(60, amplitude()).frequency, Some(60));
+    }
+
+    #[test]
+    fn nothing_in_the_noise() {
+        let report = detect(50, 0.0);
+        assert_eq!(report.frequency, None);
+        assert!(report.power < HUM_MIN_POWER);
+    }
+
+    #[test]
+    fn no_report_before_enough_samples() {
+        let mut detector = HumDetector::new();
+        let start = Instant::now();
+        for i in 0..HUM_MIN_SAMPLES as u64 - 1 {
+            detector.add(start + HUM_WINDOW * (i as u32 + 1), i % 2 * 100);
+        }
+        assert_eq!(detector.report().power, 0);
+    }
+
+    #[test]
+    fn mains_sync_keeps_detecting() {
+        let hum = HumReport {
+            frequency: Some(50),
+            amplitude: 0,
+            power: 0,
+        };
+        let mut scheduler = SampleScheduler::new(SampleTiming::MainsSync);
+        let random_phase = (0..2 * HUM_DETECTION_INTERVAL)
+            .filter(|_| block_on(scheduler.wait(&hum)))
+            .count();
+        assert_eq!(random_phase, 2);
+
+        let mut scheduler = SampleScheduler::new(SampleTiming::Fixed);
+        assert!(!block_on(scheduler.wait(&hum)));
+        let mut scheduler = SampleScheduler::new(SampleTiming::Dithered);
+        assert!(block_on(scheduler.wait(&hum)));
+    }
+}
@@ -0,0 +1,396 @@
+// Keyboard logic: turns sensor states into MIDI messages and LED colors
+
+use defmt::{debug, info};
+use embassy_time::Instant;
+
+use crate::accessory::Accessory;
+use crate::aftertouch::AftertouchTracker;
+use crate::config::*;
+use crate::material::{Materials, MATERIALS};
+use crate::midi::{Channel, MidiMsg, MidiSink, U7};
+use crate::proximity::ProximityController;
+use crate::slider::SliderController;
+use crate::touch_events::{TouchEventKind, TouchEvents};
+use crate::touch_sensors::{CalibrationDataSet, CalibrationStatus, TouchSensorStatus};
+use crate::touch_source::TouchSource;
+use crate::velocity::VelocityTracker;
+
+/// Output for the key LED colors
+#[allow(async_fn_in_trait)]
+pub trait LedStrip {
+    async fn write(&mut self, colors: &[u32]);
+}
+
+pub struct Keyboard {
+    accessory: &'static Accessory,
+    // notes of the sensors, by the accessory and the material bindings
+    notes: [Option<U7>; NUM_SENSORS],
+    channels: [Channel; NUM_SENSORS],
+    program_changes: [Option<MidiMsg>; MATERIALS.len()],
+    status: [TouchSensorStatus; NUM_SENSORS],
+    velocity: VelocityTracker,
+    aftertouch: AftertouchTracker,
+    proximity: ProximityController,
+    sliders: SliderController,
+    colors: [u32; NUM_LEDS],
+}
+
+impl Keyboard {
+    pub fn new(accessory: &'static Accessory, calibration: &CalibrationDataSet) -> Self {
+        let mut colors = [COL_UNUSED; NUM_LEDS];
+        for (i, which_led) in accessory.leds.iter().enumerate() {
+            let color = match which_led {
+                None => {
+                    continue;
+                }
+                Some(led) => &mut colors[*led],
+            };
+            let status = calibration.pins[i].status;
+            let piano_key = accessory.piano_keys[i];
+            *color = match (status, piano_key) {
+                (CalibrationStatus::NA, PianoKey::White) => COL_WHITE_OFF,
+                (CalibrationStatus::NA, PianoKey::Black) => COL_BLACK_OFF,
+                (CalibrationStatus::Ok, PianoKey::White) => COL_WHITE_OFF,
+                (CalibrationStatus::Ok, PianoKey::Black) => COL_BLACK_OFF,
+                (CalibrationStatus::Bad, _) => COL_BROKEN,
+                (_, PianoKey::Missing) => COL_UNUSED,
+            }
+        }
+        let materials = Materials::classify(calibration);
+        info!("Materials: {}", materials);
+        Self {
+            accessory,
+            notes: core::array::from_fn(|i| materials.note(i, accessory.note(i))),
+            channels: core::array::from_fn(|i| materials.channel(i)),
+            program_changes: materials.program_changes(),
+            status: [TouchSensorStatus::NA; NUM_SENSORS],
+            velocity: VelocityTracker::new(VELOCITY_CURVE),
+            aftertouch: AftertouchTracker::new(),
+            proximity: ProximityController::new(PROXIMITY_SENSORS),
+            sliders: SliderController::new(SLIDERS),
+            colors,
+        }
+    }
+    /// Program changes bound to the materials of the sensors
+    pub fn program_changes(&self) -> impl Iterator<Item = MidiMsg> + '_ {
+        self.program_changes.iter().flatten().copied()
+    }
+    pub fn colors(&self) -> &[u32; NUM_LEDS] {
+        &self.colors
+    }
+    /// Analog levels are needed from the touch source
+    pub fn needs_levels(&self) -> bool {
+        self.velocity.needs_levels()
+            || AFTERTOUCH_ENABLED
+            || self.proximity.is_active()
+            || self.sliders.is_active()
+    }
+    /// Process the events from a single scan of `source`
+    pub async fn update(
+        &mut self,
+        source: &impl TouchSource,
+        events: &TouchEvents,
+        now: Instant,
+        midi: &impl MidiSink,
+        leds: &mut impl LedStrip,
+    ) {
+        let accessory = self.accessory;
+        self.velocity.update(&source.levels());
+        for event in events.iter() {
+            let i = event.sensor;
+            let was_on = self.status[i] == TouchSensorStatus::On;
+            self.status[i] = match event.kind {
+                TouchEventKind::Touched => TouchSensorStatus::On,
+                TouchEventKind::Released => TouchSensorStatus::Off,
+                TouchEventKind::Broken => TouchSensorStatus::Broken,
+            };
+            if self.proximity.is_controller(i) || self.sliders.is_pad(i) {
+                continue;
+            }
+            let piano_key = accessory.piano_keys[i];
+            let color = match (event.kind, piano_key) {
+                (_, PianoKey::Missing) => COL_UNUSED,
+                (TouchEventKind::Released, PianoKey::White) => COL_WHITE_OFF,
+                (TouchEventKind::Released, PianoKey::Black) => COL_BLACK_OFF,
+                (TouchEventKind::Touched, PianoKey::White) => COL_WHITE_ON,
+                (TouchEventKind::Touched, PianoKey::Black) => COL_BLACK_ON,
+                (TouchEventKind::Broken, _) => COL_BROKEN,
+            };
+            let maybe_note_nr = self.notes[i];
+            self.aftertouch.reset(i);
+
+            match (event.kind, maybe_note_nr) {
+                (TouchEventKind::Touched, Some(note_nr)) => {
+                    let msg = MidiMsg::NoteOn {
+                        channel: self.channels[i],
+                        note: note_nr,
+                        velocity: self.velocity.velocity(i, source.threshold_level(i)),
+                    };
+                    info!("Midi: {}", msg);
+                    midi.try_send(msg); // ignore error (buffer full)
+                }
+                (TouchEventKind::Released, Some(note_nr))
+                | (TouchEventKind::Broken, Some(note_nr))
+                    if event.kind == TouchEventKind::Released || was_on =>
+                {
+                    let msg = MidiMsg::NoteOff {
+                        channel: self.channels[i],
+                        note: note_nr,
+                        velocity: U7::MIN,
+                    };
+                    info!("Midi: {}", msg);
+                    midi.send(msg).await; // wait until this note-off can be sent
+                }
+                _ => (),
+            };
+            if let Some(led) = accessory.leds[i] {
+                self.colors[led] = color;
+                leds.write(&self.colors).await;
+            }
+        }
+        if AFTERTOUCH_ENABLED {
+            let levels = source.levels();
+            for (i, status) in self.status.iter().enumerate() {
+                if *status != TouchSensorStatus::On
+                    || self.proximity.is_controller(i)
+                    || self.sliders.is_pad(i)
+                {
+                    continue;
+                }
+                let Some(note_nr) = self.notes[i] else {
+                    continue;
+                };
+                let threshold_level = source.threshold_level(i);
+                if let Some(pressure) = self.aftertouch.update(i, levels[i], threshold_level, now) {
+                    let msg = MidiMsg::PolyPressure {
+                        channel: self.channels[i],
+                        note: note_nr,
+                        pressure,
+                    };
+                    debug!("Midi: {}", msg);
+                    midi.try_send(msg); // ignore error (buffer full)
+                }
+            }
+        }
+        if self.proximity.is_active() {
+            let levels = source.levels();
+            for (i, level) in levels.iter().enumerate() {
+                if !self.proximity.is_controller(i) {
+                    continue;
+                }
+                let msgs = match self.status[i] {
+                    TouchSensorStatus::Broken => [self.proximity.release(i), None],
+                    _ => self.proximity.update(i, *level, now),
+                };
+                for msg in msgs.into_iter().flatten() {
+                    send_or_drop(midi, msg).await;
+                }
+            }
+        }
+        if self.sliders.is_active() {
+            for msg in self
+                .sliders
+                .update(&source.levels(), now)
+                .into_iter()
+                .flatten()
+            {
+                send_or_drop(midi, msg).await;
+            }
+        }
+    }
+    /// Send note-offs for all the notes being played
+    pub async fn release_all(&mut self, midi: &impl MidiSink) {
+        for (i, status) in self.status.iter_mut().enumerate() {
+            if let (TouchSensorStatus::On, Some(note_nr)) = (*status, self.notes[i]) {
+                let msg = MidiMsg::NoteOff {
+                    channel: self.channels[i],
+                    note: note_nr,
+                    velocity: U7::MIN,
+                };
+                info!("Midi: {}", msg);
+                midi.send(msg).await;
+            }
+            *status = TouchSensorStatus::NA;
+            if let Some(msg) = self.proximity.release(i) {
+                info!("Midi: {}", msg);
+                midi.send(msg).await;
+            }
+        }
+        for msg in self.sliders.release_all().into_iter().flatten() {
+            info!("Midi: {}", msg);
+            midi.send(msg).await;
+        }
+    }
+}
+
+/// Send `msg` if there is room, but wait for room for a note-off, a lost one
+/// would leave the note hanging
+async fn send_or_drop(midi: &impl MidiSink, msg: MidiMsg) {
+    debug!("Midi: {}", msg);
+    if let MidiMsg::NoteOff { .. } = msg {
+        midi.send(msg).await;
+    } else {
+        midi.try_send(msg); // ignore error (buffer full)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+
+    use embassy_futures::block_on;
+    use embassy_time::Duration;
+
+    use super::*;
+    use crate::touch_events::TouchEventDetector;
+    use crate::touch_source::SimulatedTouchSource;
+
+    #[derive(Default)]
+    struct TestMidi(RefCell<Vec<MidiMsg>>);
+
+    impl MidiSink for TestMidi {
+        async fn send(&self, msg: MidiMsg) {
+            self.0.borrow_mut().push(msg);
+        }
+        fn try_send(&self, msg: MidiMsg) -> bool {
+            self.0.borrow_mut().push(msg);
+            true
+        }
+    }
+
+    #[derive(Default)]
+    struct TestLeds(Vec<u32>);
+
+    impl LedStrip for TestLeds {
+        async fn write(&mut self, colors: &[u32]) {
+            self.0 = colors.to_vec();
+        }
+    }
+
+    struct Rig {
+        source: SimulatedTouchSource,
+        detector: TouchEventDetector,
+        keyboard: Keyboard,
+        midi: TestMidi,
+        leds: TestLeds,
+        now: Instant,
+    }
+
+    impl Rig {
+        fn new() -> Self {
+            let mut source = SimulatedTouchSource::new();
+            let calibration = block_on(source.calibrate_idle(Duration::from_secs(1)));
+            let keyboard = Keyboard::new(&ACCESSORIES[0], &calibration);
+            source.set_analog(keyboard.needs_levels());
+            let mut rig = Self {
+                source,
+                detector: TouchEventDetector::new(),
+                keyboard,
+                midi: TestMidi::default(),
+                leds: TestLeds::default(),
+                now: Instant::from_ticks(0),
+            };
+            // the first scan reports every sensor released
+            rig.scan();
+            rig
+        }
+        /// Messages sent for a scan of the current sensor states
+        fn scan(&mut self) -> Vec<MidiMsg> {
+            self.now += Duration::from_millis(1);
+            let status = block_on(self.source.run());
+            let events = self
+                .detector
+                .update(&status, &self.source.levels(), self.now);
+            block_on(self.keyboard.update(
+                &self.source,
+                &events,
+                self.now,
+                &self.midi,
+                &mut self.leds,
+            ));
+            self.midi.0.take()
+        }
+        fn release_all(&mut self) -> Vec<MidiMsg> {
+            block_on(self.keyboard.release_all(&self.midi));
+            self.midi.0.take()
+        }
+    }
+
+    fn note_on(note: u8, velocity: u8) -> MidiMsg {
+        MidiMsg::NoteOn {
+            channel: MIDI_OUT_CHANNEL,
+            note: U7::new(note).unwrap(),
+            velocity: U7::new(velocity).unwrap(),
+        }
+    }
+
+    fn note_off(note: u8) -> MidiMsg {
+        MidiMsg::NoteOff {
+            channel: MIDI_OUT_CHANNEL,
+            note: U7::new(note).unwrap(),
+            velocity: U7::MIN,
+        }
+    }
+
+    #[test]
+    fn touch_and_release_play_a_note() {
+        let mut rig = Rig::new();
+        // sensor 1 is the C of the default accessory, on LED 8
+        rig.source.set_level(1, 1000);
+        assert!(rig.scan() == vec![note_on(60, 127)]);
+        assert_eq!(rig.leds.0[8], COL_WHITE_ON);
+        // held, nothing more
+        assert!(rig.scan().is_empty());
+        rig.source.set_level(1, 0);
+        assert!(rig.scan() == vec![note_off(60)]);
+        assert_eq!(rig.leds.0[8], COL_WHITE_OFF);
+    }
+
+    #[test]
+    fn velocity_follows_the_level() {
+        let mut rig = Rig::new();
+        // from 0 to a fifth of the way from the threshold (500) to the top in one
+        // scan: speed 600 and depth 200 weighed half and half give velocity 51
+        rig.source.set_level(2, 600);
+        assert!(rig.scan() == vec![note_on(61, 51)]);
+        assert_eq!(rig.leds.0[SENSOR_TO_LED[2].unwrap()], COL_BLACK_ON);
+    }
+
+    #[test]
+    fn chord_in_sensor_order() {
+        let mut rig = Rig::new();
+        for i in [5, 0, 3] {
+            rig.source.set_level(i, 1000);
+        }
+        assert!(rig.scan() == vec![note_on(59, 127), note_on(62, 127), note_on(64, 127)]);
+    }
+
+    #[test]
+    fn broken_sensor_stops_its_note() {
+        let mut rig = Rig::new();
+        rig.source.set_level(1, 1000);
+        rig.scan();
+        rig.source.status[1] = TouchSensorStatus::Broken;
+        assert!(rig.scan() == vec![note_off(60)]);
+        assert_eq!(rig.leds.0[8], COL_BROKEN);
+        // not playing: nothing to stop
+        rig.source.status[3] = TouchSensorStatus::Broken;
+        assert!(rig.scan().is_empty());
+    }
+
+    #[test]
+    fn release_all_stops_the_held_notes() {
+        let mut rig = Rig::new();
+        rig.source.set_level(0, 1000);
+        rig.source.set_level(3, 1000);
+        rig.scan();
+        assert!(rig.release_all() == vec![note_off(59), note_off(62)]);
+        assert!(rig.release_all().is_empty());
+    }
+
+    #[test]
+    fn no_program_change_without_material_binding() {
+        let rig = Rig::new();
+        assert!(rig.keyboard.program_changes().next().is_none());
+    }
+}
@@ -1,13 +1,63 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
-// for other binaries (test tools)
+// for other binaries (test tools), and the host unit tests of the modules
+// not touching the hardware:
+// cargo test --lib --target x86_64-unknown-linux-gnu
 
+pub mod accessory;
+#[cfg(target_os = "none")]
 pub mod adc;
+pub mod aftertouch;
+#[cfg(target_os = "none")]
 pub mod board;
+#[cfg(target_os = "none")]
 pub mod button;
+pub mod calibration_stats;
 pub mod config;
+pub mod crosstalk;
+pub mod din_output;
+#[cfg(target_os = "none")]
+pub mod gpio_sensors;
+pub mod hum;
+pub mod keyboard;
+pub mod material;
+pub mod math;
 pub mod midi;
+pub mod midi_router;
+pub mod mpr121;
+#[cfg(all(target_os = "none", feature = "pio-sensors"))]
+pub mod pio_sensors;
+pub mod proximity;
+pub mod sensor_health;
+#[cfg(target_os = "none")]
 pub mod serial_midi;
+pub mod slider;
+pub mod touch_events;
+pub mod touch_filter;
 pub mod touch_sensors;
+pub mod touch_source;
+#[cfg(target_os = "none")]
 pub mod usb_midi;
+pub mod usb_midi_packet;
+pub mod velocity;
+#[cfg(target_os = "none")]
 pub mod ws2812b;
+
+// the log output of the host unit tests goes nowhere
+#[cfg(test)]
+mod test_logger {
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    #[defmt::panic_handler]
+    fn panic() -> ! {
+        panic!("defmt panic")
+    }
+}
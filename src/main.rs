@@ -9,29 +9,65 @@ use core::ptr::addr_of_mut;
 use defmt::{unreachable, *};
 use embassy_executor::Executor;
 use embassy_futures::join::join3;
+use embassy_futures::select::{select4, Either4};
 use embassy_rp::multicore::{spawn_core1, Stack};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Ticker, Timer};
 use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
+mod accessory;
 mod adc;
+mod aftertouch;
 mod board;
 mod button;
+mod calibration_stats;
 mod config;
+mod crosstalk;
+mod din_output;
+#[cfg(not(any(feature = "pio-sensors", feature = "mpr121-sensors")))]
+mod gpio_sensors;
+mod hum;
+mod keyboard;
+mod material;
+mod math;
 mod midi;
+mod midi_router;
+#[cfg(feature = "mpr121-sensors")]
+mod mpr121;
+#[cfg(feature = "pio-sensors")]
+mod pio_sensors;
+mod proximity;
+mod sensor_health;
 mod serial_midi;
+mod slider;
+mod touch_events;
+mod touch_filter;
 mod touch_sensors;
+mod touch_source;
 mod usb_midi;
+mod usb_midi_packet;
+mod velocity;
 mod ws2812b;
 
+use crate::accessory::AccessoryDetector;
 use crate::adc::{Adc, AdcValues};
 use crate::button::Button;
 use crate::config::*;
-use crate::midi::{
-    MidiChannel, MidiChannelMC, MidiChannelMCReceiver, MidiChannelMCSender, MidiMsg,
-};
+#[cfg(not(any(feature = "pio-sensors", feature = "mpr121-sensors")))]
+use crate::gpio_sensors::GpioDischargeTimer;
+use crate::keyboard::Keyboard;
+use crate::midi::{MidiChannel, MidiChannelMC, MidiChannelMCReceiver, MidiChannelMCSender};
+use crate::midi_router::{ConnectionSignal, OutputRoute};
+#[cfg(feature = "mpr121-sensors")]
+use crate::mpr121::Mpr121TouchSource;
+#[cfg(all(feature = "pio-sensors", not(feature = "mpr121-sensors")))]
+use crate::pio_sensors::PioDischargeTimer;
 use crate::serial_midi::SerialMidi;
-use crate::touch_sensors::{CalibrationStatus, TouchSensorStatus, TouchSensors};
+use crate::touch_events::{TouchEventChannel, TouchEventDetector, TouchEvents};
+use crate::touch_sensors::CalibrationStatus;
+#[cfg(not(feature = "mpr121-sensors"))]
+use crate::touch_sensors::TouchSensors;
+use crate::touch_source::TouchSource;
 use crate::usb_midi::UsbMidi;
 use crate::ws2812b::WS2812B;
 
@@ -70,7 +106,12 @@ fn main() -> ! {
 #[embassy_executor::task]
 async fn core0_task(b: crate::board::Core0Pers, midi_tx: MidiChannelMCSender<'static>) -> ! {
     let leds = WS2812B::new(b.leds_pio, b.leds_pin);
-    let sensors = TouchSensors::new(b.sensor_pins);
+    #[cfg(not(any(feature = "pio-sensors", feature = "mpr121-sensors")))]
+    let sensors = TouchSensors::new(GpioDischargeTimer::new(b.sensor_pins));
+    #[cfg(all(feature = "pio-sensors", not(feature = "mpr121-sensors")))]
+    let sensors = TouchSensors::new(PioDischargeTimer::new(b.sensor_pins));
+    #[cfg(feature = "mpr121-sensors")]
+    let sensors = Mpr121TouchSource::new(b.mpr121_i2c, b.mpr121_irq);
 
     let adc_values = AdcValues::new();
     let button = Button::new(b.button_in);
@@ -79,7 +120,9 @@ async fn core0_task(b: crate::board::Core0Pers, midi_tx: MidiChannelMCSender<'st
     let bt_task = button.task();
     let adc_task = adc.task();
 
-    let main_task = measure_task(leds, sensors, &button, &adc_values, midi_tx);
+    static TOUCH_EVENTS: TouchEventChannel = TouchEventChannel::new();
+
+    let main_task = measure_task(leds, sensors, &button, &adc_values, midi_tx, &TOUCH_EVENTS);
 
     join3(main_task, bt_task, adc_task).await;
 
@@ -89,20 +132,53 @@ async fn core0_task(b: crate::board::Core0Pers, midi_tx: MidiChannelMCSender<'st
 #[embassy_executor::task]
 async fn core1_task(b: crate::board::Core1Pers, midi_c0_rx: MidiChannelMCReceiver<'static>) -> ! {
     let serial_midi_channel = MidiChannel::new();
-    let serial_midi_tx = serial_midi_channel.sender();
     let usb_midi_channel = MidiChannel::new();
-    let usb_midi_tx = usb_midi_channel.sender();
+    let usb_connection = ConnectionSignal::new();
     let mut serial_midi =
         SerialMidi::new(b.midi_uart, b.midi_tx_pin, serial_midi_channel.receiver());
-    let mut usb_midi = UsbMidi::new(b.midi_usb, usb_midi_channel.receiver());
+    let mut usb_midi = UsbMidi::new(b.midi_usb, usb_midi_channel.receiver(), &usb_connection);
     let serial_midi_task = serial_midi.task();
     let usb_midi_task = usb_midi.task();
 
     let midi_router_task = async {
+        let mut serial_route = OutputRoute::new("serial", serial_midi_channel.sender(), true);
+        let mut usb_route = OutputRoute::new("usb", usb_midi_channel.sender(), false);
+        let mut report = Ticker::every(MIDI_DROPPED_REPORT_INTERVAL);
         loop {
-            let msg = midi_c0_rx.receive().await;
-            serial_midi_tx.try_send(msg).ok();
-            usb_midi_tx.try_send(msg).ok();
+            let pending = serial_route.has_pending() || usb_route.has_pending();
+            let retry = async {
+                if pending {
+                    Timer::after(MIDI_RETRY_INTERVAL).await
+                } else {
+                    core::future::pending().await
+                }
+            };
+            match select4(
+                midi_c0_rx.receive(),
+                usb_connection.wait(),
+                retry,
+                report.next(),
+            )
+            .await
+            {
+                Either4::First(msg) => {
+                    serial_route.route(msg);
+                    usb_route.route(msg);
+                }
+                Either4::Second(states) => {
+                    for connected in states.into_iter().flatten() {
+                        usb_route.set_connected(connected);
+                    }
+                }
+                Either4::Third(_) => {
+                    serial_route.flush();
+                    usb_route.flush();
+                }
+                Either4::Fourth(_) => {
+                    serial_route.report_dropped();
+                    usb_route.report_dropped();
+                }
+            }
         }
     };
 
@@ -113,11 +189,16 @@ async fn core1_task(b: crate::board::Core1Pers, midi_c0_rx: MidiChannelMCReceive
 
 async fn measure_task<'a>(
     mut leds: WS2812B,
-    mut sensors: TouchSensors<'a>,
+    mut sensors: impl TouchSource,
     button: &Button<'a>,
     adc_values: &'a AdcValues,
     midi_tx: MidiChannelMCSender<'a>,
+    touch_events: &'a TouchEventChannel,
 ) {
+    let events_tx = unwrap!(touch_events.publisher());
+    // the keyboard plays the published events like any other subscriber
+    let mut events_rx = unwrap!(touch_events.subscriber());
+
     let mut colors = [COL_UNUSED; NUM_LEDS];
 
     for i in 0..NUM_LEDS {
@@ -131,126 +212,105 @@ async fn measure_task<'a>(
         Timer::after_millis(50).await;
     }
 
+    let mut detector = AccessoryDetector::new(
+        adc_values
+            .get_value(ACCESSORY_ADC_INPUT, 1000)
+            .unwrap_or(1000),
+    );
+
+    // the first calibration may be done without the button
+    let mut auto_calibrate = AUTO_CALIBRATION;
     loop {
+        let accessory = detector.current();
+        info!("Accessory: {}", accessory.name);
+
         colors = [COL_CAL_NA; NUM_LEDS];
         leds.write(&colors).await;
 
-        sensors.calibrate_start().await;
-        let mut cycle = 0;
-        while !button.was_pressed() {
-            let calib = sensors.calibrate_step().await;
-            info!("{}", calib);
-            info!("sens: {}%", adc_values.get_value(0, 100));
-            for (i, which_led) in SENSOR_TO_LED.iter().enumerate().take(NUM_SENSORS) {
-                let color = match which_led {
-                    None => {
-                        continue;
-                    }
-                    Some(led) => &mut colors[*led],
-                };
-                let status = calib.pins[i].status;
-                *color = match status {
-                    CalibrationStatus::NA => COL_CAL_NA,
-                    CalibrationStatus::Ok => COL_CAL_OK,
-                    CalibrationStatus::Bad => COL_CAL_BAD,
-                } * if i == cycle { 4 } else { 1 };
+        let mut accessory_changed = false;
+        let result = if auto_calibrate {
+            auto_calibrate = false;
+            sensors.calibrate_idle(AUTO_CALIBRATION_TIME).await
+        } else {
+            sensors.calibrate_start().await;
+            let mut cycle = 0;
+            while !button.was_pressed() {
+                let accessory_id = adc_values.get_value(ACCESSORY_ADC_INPUT, 1000);
+                if detector.update(accessory_id.unwrap_or(1000), Instant::now()) {
+                    info!("Accessory changed: {}", detector.current().name);
+                    accessory_changed = true;
+                    break;
+                }
+                let calib = sensors.calibrate_step().await;
+                info!("{}", calib);
+                info!("sens: {}%", adc_values.get_value(0, 100));
+                for (i, which_led) in accessory.leds.iter().enumerate() {
+                    let color = match which_led {
+                        None => {
+                            continue;
+                        }
+                        Some(led) => &mut colors[*led],
+                    };
+                    let pin_c = calib.pins[i];
+                    *color = match pin_c.status {
+                        CalibrationStatus::NA => COL_CAL_NA,
+                        CalibrationStatus::Ok
+                            if pin_c.quality.is_some_and(|q| q < CALIBRATION_QUALITY_POOR) =>
+                        {
+                            COL_CAL_POOR
+                        }
+                        CalibrationStatus::Ok => COL_CAL_OK,
+                        CalibrationStatus::Bad => COL_CAL_BAD,
+                    } * if i == cycle { 4 } else { 1 };
+                }
+                leds.write(&colors).await;
+                cycle = (cycle + 1) % NUM_SENSORS;
+                Timer::after_millis(100).await;
             }
-            leds.write(&colors).await;
-            cycle = (cycle + 1) % NUM_SENSORS;
-            Timer::after_millis(100).await;
+            sensors.calibrate_stop().await
+        };
+        if accessory_changed {
+            // start over, calibrating the new sensors
+            auto_calibrate = AUTO_CALIBRATION;
+            continue;
         }
-        let result = sensors.calibrate_stop().await;
+        info!("{}", result);
         if result.status != CalibrationStatus::Ok {
             continue;
         }
 
-        colors = [COL_UNUSED; NUM_LEDS];
-        for (i, which_led) in SENSOR_TO_LED.iter().enumerate().take(NUM_SENSORS) {
-            let color = match which_led {
-                None => {
-                    continue;
-                }
-                Some(led) => &mut colors[*led],
-            };
-            let status = result.pins[i].status;
-            let piano_key = SENSOR_TO_PIANO_KEY[i];
-            *color = match (status, piano_key) {
-                (CalibrationStatus::NA, PianoKey::White) => COL_WHITE_OFF,
-                (CalibrationStatus::NA, PianoKey::Black) => COL_BLACK_OFF,
-                (CalibrationStatus::Ok, PianoKey::White) => COL_WHITE_OFF,
-                (CalibrationStatus::Ok, PianoKey::Black) => COL_BLACK_OFF,
-                (CalibrationStatus::Bad, _) => COL_BROKEN,
-                (_, PianoKey::Missing) => COL_UNUSED,
-            }
+        let mut keyboard = Keyboard::new(accessory, &result);
+        let mut touches = TouchEventDetector::new();
+        for msg in keyboard.program_changes() {
+            midi_tx.send(msg).await;
         }
-        leds.write(&colors).await;
+        leds.write(keyboard.colors()).await;
+        sensors.set_analog(keyboard.needs_levels());
 
-        let mut prev_status = [TouchSensorStatus::NA; NUM_SENSORS];
         while !button.was_pressed() {
+            let accessory_id = adc_values.get_value(ACCESSORY_ADC_INPUT, 1000);
+            if detector.update(accessory_id.unwrap_or(1000), Instant::now()) {
+                info!("Accessory changed: {}", detector.current().name);
+                // the new sensors need calibration
+                auto_calibrate = AUTO_CALIBRATION;
+                break;
+            }
             let sens = adc_values.get_value(0, 1000).unwrap_or(500);
-            sensors.set_sensitivity(sens);
+            sensors.set_sensitivity(accessory.sensitivity(sens));
             let status = sensors.run().await;
-            debug!("Status: {}", status);
-            debug!("Previous: {}", prev_status);
-            for (i, (prev, cur)) in prev_status.iter_mut().zip(status.iter()).enumerate() {
-                if *cur == *prev {
-                    continue;
-                }
-                match *cur {
-                    TouchSensorStatus::NA => continue,
-                    TouchSensorStatus::On => {
-                        info!("Touched: {}", i);
-                    }
-                    TouchSensorStatus::Off => {
-                        info!("Released: {}", i);
-                    }
-                };
-                *prev = *cur;
-                let piano_key = SENSOR_TO_PIANO_KEY[i];
-                let color = match (*cur, piano_key) {
-                    (TouchSensorStatus::Off, PianoKey::White) => COL_WHITE_OFF,
-                    (TouchSensorStatus::Off, PianoKey::Black) => COL_BLACK_OFF,
-                    (TouchSensorStatus::On, PianoKey::White) => COL_WHITE_ON,
-                    (TouchSensorStatus::On, PianoKey::Black) => COL_BLACK_ON,
-                    (_, PianoKey::Missing) => COL_UNUSED,
-                    _ => unreachable!(),
-                };
-                let maybe_note_nr = if let Some(nr) = SENSOR_TO_NOTE[i] {
-                    let note_nr = ROOT_NOTE + nr;
-                    if note_nr >= 0 {
-                        Some(note_nr)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                };
-
-                match (*cur, maybe_note_nr) {
-                    (TouchSensorStatus::On, Some(note_nr)) => {
-                        let msg = MidiMsg::NoteOn {
-                            note: note_nr,
-                            velocity: 64,
-                        };
-                        info!("Midi: {}", msg);
-                        midi_tx.try_send(msg).ok(); // ignore error (buffer full)
-                    }
-                    (TouchSensorStatus::Off, Some(note_nr)) => {
-                        let msg = MidiMsg::NoteOff {
-                            note: note_nr,
-                            velocity: 0,
-                        };
-                        info!("Midi: {}", msg);
-                        midi_tx.send(msg).await; // wait until this note-off can be sent
-                    }
-                    _ => (),
-                };
-                if let Some(led) = SENSOR_TO_LED[i] {
-                    colors[led] = color;
-                    leds.write(&colors).await;
-                }
-            }
+            let now = Instant::now();
+            touches
+                .update(&status, &sensors.levels(), now)
+                .publish(&events_tx);
+            let scan_events = TouchEvents::receive(&mut events_rx);
+            keyboard
+                .update(&sensors, &scan_events, now, &midi_tx, &mut leds)
+                .await;
             Timer::after_millis(2).await;
         }
+        keyboard.release_all(&midi_tx).await;
+        touches.release_all(Instant::now()).publish(&events_tx);
+        // the keyboard has stopped its notes already, the next one starts afresh
+        while events_rx.try_next_message_pure().is_some() {}
     }
 }
@@ -0,0 +1,162 @@
+// What is attached to a sensor, guessed from its idle discharge time:
+// the bigger the object, the higher its capacitance and the longer it takes
+
+use defmt::{write, Format, Formatter};
+
+use crate::config::*;
+use crate::midi::{Channel, MidiMsg, U7};
+use crate::touch_sensors::{CalibrationData, CalibrationDataSet};
+
+#[derive(Clone, Copy, Format, PartialEq)]
+pub enum Material {
+    // bare connector
+    Nothing,
+    // direct wire or a small metal part
+    Wire,
+    SmallFruit,
+    // large conductive body: melon, bowl of water, metal plate...
+    LargeBody,
+    Short,
+}
+
+pub const MATERIALS: [Material; 5] = [
+    Material::Nothing,
+    Material::Wire,
+    Material::SmallFruit,
+    Material::LargeBody,
+    Material::Short,
+];
+
+impl Material {
+    /// Classify a sensor from its calibration
+    pub fn classify(pin_c: &CalibrationData) -> Material {
+        let idle = pin_c.min_time;
+        if idle < MIN_TIME_REQUIRED {
+            Material::Short
+        } else if idle < MATERIAL_WIRE_MIN {
+            Material::Nothing
+        } else if idle < MATERIAL_FRUIT_MIN {
+            Material::Wire
+        } else if idle < MATERIAL_LARGE_MIN {
+            Material::SmallFruit
+        } else {
+            // also never discharged within the time limit (Duration::MAX):
+            // too much to discharge
+            Material::LargeBody
+        }
+    }
+    pub fn binding(&self) -> &'static MaterialBinding {
+        &MATERIAL_BINDINGS[*self as usize]
+    }
+}
+
+/// What a material class plays (see `MATERIAL_BINDINGS`)
+pub struct MaterialBinding {
+    // channel the sensors of this material play on instead of MIDI_OUT_CHANNEL,
+    // classes sharing a channel share its program too
+    pub channel: Option<Channel>,
+    // program change sent on that channel when a sensor is of this material
+    pub program: Option<U7>,
+    // notes (absolute MIDI numbers) of the sensors of this material, in sensor
+    // order, instead of the accessory notes; sensors past the end play nothing
+    pub notes: Option<(U7, U7)>,
+}
+
+/// Materials of all the sensors, for the diagnostics
+pub struct Materials(pub [Material; NUM_SENSORS]);
+
+impl Materials {
+    pub fn classify(calibration: &CalibrationDataSet) -> Self {
+        Self(calibration.pins.map(|pin_c| Material::classify(&pin_c)))
+    }
+    /// Channel sensor `i` plays on
+    pub fn channel(&self, i: usize) -> Channel {
+        self.0[i].binding().channel.unwrap_or(MIDI_OUT_CHANNEL)
+    }
+    /// Program changes bound to the materials of the sensors, one per class found
+    pub fn program_changes(&self) -> [Option<MidiMsg>; MATERIALS.len()] {
+        MATERIALS.map(|material| {
+            let binding = material.binding();
+            let program = binding.program.filter(|_| self.0.contains(&material))?;
+            Some(MidiMsg::ProgramChange {
+                channel: binding.channel.unwrap_or(MIDI_OUT_CHANNEL),
+                program,
+            })
+        })
+    }
+    /// Note of sensor `i` assigned by the binding of its material, `default`
+    /// if the material has no notes bound
+    pub fn note(&self, i: usize, default: Option<U7>) -> Option<U7> {
+        let material = self.0[i];
+        let Some((low, high)) = material.binding().notes else {
+            return default;
+        };
+        let index = self.0[..i].iter().filter(|&&m| m == material).count();
+        low.checked_add(index as i32).filter(|note| *note <= high)
+    }
+}
+
+impl Format for Materials {
+    fn format(&self, f: Formatter) {
+        for (i, material) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ");
+            }
+            write!(f, "{}: {}", i, material);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::Duration;
+
+    use super::*;
+
+    fn classify(idle: Duration) -> Material {
+        Material::classify(&CalibrationData {
+            min_time: idle,
+            ..CalibrationData::default()
+        })
+    }
+
+    #[test]
+    fn classify_by_the_idle_time() {
+        let us = Duration::from_micros;
+        assert!(classify(MIN_TIME_REQUIRED - us(1)) == Material::Short);
+        assert!(classify(MIN_TIME_REQUIRED) == Material::Nothing);
+        assert!(classify(MATERIAL_WIRE_MIN - us(1)) == Material::Nothing);
+        assert!(classify(MATERIAL_WIRE_MIN) == Material::Wire);
+        assert!(classify(MATERIAL_FRUIT_MIN) == Material::SmallFruit);
+        assert!(classify(MATERIAL_LARGE_MIN - us(1)) == Material::SmallFruit);
+        assert!(classify(MATERIAL_LARGE_MIN) == Material::LargeBody);
+    }
+
+    #[test]
+    fn never_discharged_is_a_large_body() {
+        assert!(classify(Duration::MAX) == Material::LargeBody);
+    }
+
+    #[test]
+    fn bindings_in_the_order_of_the_materials() {
+        assert_eq!(MATERIAL_BINDINGS.len(), MATERIALS.len());
+        for (i, material) in MATERIALS.iter().enumerate() {
+            assert_eq!(*material as usize, i);
+        }
+    }
+
+    // with the default MATERIAL_BINDINGS, which bind nothing
+    #[test]
+    fn unbound_materials_keep_the_accessory_notes() {
+        let mut calibration = CalibrationDataSet::default();
+        calibration.pins[0].min_time = MATERIAL_FRUIT_MIN;
+        let materials = Materials::classify(&calibration);
+        assert!(materials.0[0] == Material::SmallFruit);
+        assert!(materials.0[1] == Material::LargeBody);
+        let note = U7::new(60);
+        assert!(materials.note(0, note) == note);
+        assert!(materials.note(1, None).is_none());
+        assert!(materials.channel(0) == MIDI_OUT_CHANNEL);
+        assert!(materials.program_changes().iter().all(Option::is_none));
+    }
+}
@@ -0,0 +1,65 @@
+// Small integer math helpers (no floating point hardware here)
+
+/// Integer square root (rounded down)
+pub fn isqrt(value: u32) -> u32 {
+    let mut result = 0u32;
+    let mut bit = 1u32 << 30;
+    let mut value = value;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if value >= result + bit {
+            value -= result + bit;
+            result = (result >> 1) + bit;
+        } else {
+            result >>= 1;
+        }
+        bit >>= 2;
+    }
+    result
+}
+
+/// Integer square root of a 64-bit value (rounded down)
+pub fn isqrt64(value: u64) -> u64 {
+    let mut result = 0u64;
+    let mut bit = 1u64 << 62;
+    let mut value = value;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if value >= result + bit {
+            value -= result + bit;
+            result = (result >> 1) + bit;
+        } else {
+            result >>= 1;
+        }
+        bit >>= 2;
+    }
+    result
+}
+
+// sin() of the first quarter turn, in permille, 64 steps
+const SIN_QUARTER: [i32; 65] = [
+    0, 25, 49, 74, 98, 122, 147, 171, 195, 219, 243, 267, 290, 314, 337, 360, 383, 405, 428, 450,
+    471, 493, 514, 535, 556, 576, 596, 615, 634, 653, 672, 690, 707, 724, 741, 757, 773, 788, 803,
+    818, 831, 845, 858, 870, 882, 893, 904, 914, 924, 933, 942, 950, 957, 964, 970, 976, 981, 985,
+    989, 992, 995, 997, 999, 1000, 1000,
+];
+
+/// Sine in permille, `phase` in 1/256 turns (wraps around)
+pub fn sin_permille(phase: u32) -> i32 {
+    let phase = (phase % 256) as usize;
+    match phase / 64 {
+        0 => SIN_QUARTER[phase],
+        1 => SIN_QUARTER[128 - phase],
+        2 => -SIN_QUARTER[phase - 128],
+        _ => -SIN_QUARTER[256 - phase],
+    }
+}
+
+/// Cosine in permille, `phase` in 1/256 turns (wraps around)
+pub fn cos_permille(phase: u32) -> i32 {
+    sin_permille(phase.wrapping_add(64))
+}
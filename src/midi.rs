@@ -1,46 +1,457 @@
-use defmt::Format;
-use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
-use embassy_sync::channel::{Channel, Receiver, Sender};
+// MIDI 1.0 messages and the channels carrying them between the tasks
 
-use crate::config::MIDI_CHANNEL_SIZE;
+use defmt::{debug, write, Format, Formatter};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex, RawMutex};
+use embassy_sync::channel::{self, Receiver, Sender};
+
+use crate::config::{MIDI_CHANNEL_SIZE, SYSEX_MAX_LEN};
+
+/// 7-bit data value, 0..=127
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct U7(u8);
+
+impl U7 {
+    pub const MIN: U7 = U7(0);
+    #[allow(dead_code)]
+    pub const MAX: U7 = U7(127);
+
+    #[allow(dead_code)]
+    pub const fn new(value: u8) -> Option<U7> {
+        if value <= 127 {
+            Some(U7(value))
+        } else {
+            None
+        }
+    }
+    /// `value` limited to 0..=127
+    pub const fn saturating(value: i32) -> U7 {
+        if value < 0 {
+            U7(0)
+        } else if value > 127 {
+            U7(127)
+        } else {
+            U7(value as u8)
+        }
+    }
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+    /// `self + offset`, None when out of range
+    pub fn checked_add(self, offset: i32) -> Option<U7> {
+        let value = self.0 as i32 + offset;
+        if (0..=127).contains(&value) {
+            Some(U7(value as u8))
+        } else {
+            None
+        }
+    }
+}
+
+impl TryFrom<i8> for U7 {
+    type Error = &'static str;
+
+    fn try_from(value: i8) -> Result<Self, Self::Error> {
+        if value >= 0 {
+            Ok(U7(value as u8))
+        } else {
+            Err("negative 7-bit value")
+        }
+    }
+}
+
+impl From<U7> for u8 {
+    fn from(value: U7) -> u8 {
+        value.0
+    }
+}
+
+impl Format for U7 {
+    fn format(&self, f: Formatter) {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// 14-bit data value, 0..=16383, sent as two 7-bit bytes, LSB first
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct U14(u16);
+
+impl U14 {
+    #[allow(dead_code)]
+    pub const MIN: U14 = U14(0);
+    #[allow(dead_code)]
+    pub const MAX: U14 = U14(0x3FFF);
+    // no pitch bend
+    pub const CENTER: U14 = U14(0x2000);
+
+    #[allow(dead_code)]
+    pub const fn new(value: u16) -> Option<U14> {
+        if value <= 0x3FFF {
+            Some(U14(value))
+        } else {
+            None
+        }
+    }
+    /// `value` limited to 0..=16383
+    pub const fn saturating(value: i32) -> U14 {
+        if value < 0 {
+            U14(0)
+        } else if value > 0x3FFF {
+            U14(0x3FFF)
+        } else {
+            U14(value as u16)
+        }
+    }
+    /// From a value relative to the center, -8192..=8191 (limited to it)
+    pub const fn from_signed(value: i32) -> U14 {
+        Self::saturating(value + 0x2000)
+    }
+    pub const fn from_bytes(lsb: U7, msb: U7) -> U14 {
+        U14((msb.0 as u16) << 7 | lsb.0 as u16)
+    }
+    #[allow(dead_code)]
+    pub const fn get(self) -> u16 {
+        self.0
+    }
+    /// Value relative to the center, -8192..=8191
+    #[allow(dead_code)]
+    pub const fn signed(self) -> i16 {
+        self.0 as i16 - 0x2000
+    }
+    pub const fn lsb(self) -> U7 {
+        U7((self.0 & 0x7F) as u8)
+    }
+    pub const fn msb(self) -> U7 {
+        U7((self.0 >> 7) as u8)
+    }
+}
+
+impl Format for U14 {
+    fn format(&self, f: Formatter) {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// MIDI channel, 0..=15 for the channels 1..16
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Channel(u8);
+
+impl Channel {
+    #[allow(dead_code)]
+    pub const fn new(index: u8) -> Option<Channel> {
+        if index <= 15 {
+            Some(Channel(index))
+        } else {
+            None
+        }
+    }
+    /// `index` limited to 0..=15
+    pub const fn saturating(index: u8) -> Channel {
+        if index > 15 {
+            Channel(15)
+        } else {
+            Channel(index)
+        }
+    }
+    pub const fn index(self) -> u8 {
+        self.0
+    }
+}
+
+impl Format for Channel {
+    // as numbered for the users, 1..16
+    fn format(&self, f: Formatter) {
+        write!(f, "ch{}", self.0 + 1)
+    }
+}
+
+/// Data of a system exclusive message, without the F0 and F7 framing bytes,
+/// up to `SYSEX_MAX_LEN` bytes
+#[derive(Clone, Copy, PartialEq)]
+pub struct SysEx {
+    len: usize,
+    data: [u8; SYSEX_MAX_LEN],
+}
+
+impl Default for SysEx {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            data: [0; SYSEX_MAX_LEN],
+        }
+    }
+}
+
+impl SysEx {
+    /// None when too long or when some byte is not a 7-bit value
+    #[allow(dead_code)]
+    pub fn new(data: &[u8]) -> Option<SysEx> {
+        let mut sysex = SysEx::default();
+        for &byte in data {
+            if !sysex.push(byte) {
+                return None;
+            }
+        }
+        Some(sysex)
+    }
+    /// Append a byte, returns false (and ignores it) when full or not a 7-bit value
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.len >= SYSEX_MAX_LEN || byte > 0x7F {
+            return false;
+        }
+        self.data[self.len] = byte;
+        self.len += 1;
+        true
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl Format for SysEx {
+    fn format(&self, f: Formatter) {
+        write!(f, "{=[u8]:02x}", self.data())
+    }
+}
 
 #[derive(Clone, Copy, Format, PartialEq)]
 pub enum MidiMsg {
-    NoteOn { note: i8, velocity: i8 },
-    NoteOff { note: i8, velocity: i8 },
+    // channel voice messages, a note-on with 0 velocity is kept as it is
+    NoteOn {
+        channel: Channel,
+        note: U7,
+        velocity: U7,
+    },
+    NoteOff {
+        channel: Channel,
+        note: U7,
+        velocity: U7,
+    },
+    PolyPressure {
+        channel: Channel,
+        note: U7,
+        pressure: U7,
+    },
+    ControlChange {
+        channel: Channel,
+        control: U7,
+        value: U7,
+    },
+    ProgramChange {
+        channel: Channel,
+        program: U7,
+    },
+    ChannelPressure {
+        channel: Channel,
+        pressure: U7,
+    },
+    // U14::CENTER is no bend
+    PitchBend {
+        channel: Channel,
+        value: U14,
+    },
+    // system common messages
+    // message type in the upper 3 bits, value nibble in the lower 4
+    TimeCodeQuarterFrame {
+        value: U7,
+    },
+    // in MIDI beats (sixteenth notes) from the start of the song
+    SongPosition {
+        beats: U14,
+    },
+    SongSelect {
+        song: U7,
+    },
+    TuneRequest,
+    SysEx(SysEx),
+    // system real time messages
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    SystemReset,
 }
 
 impl MidiMsg {
+    // longest serialized message, a SysEx with its framing bytes
+    pub const MAX_LEN: usize = SYSEX_MAX_LEN + 2;
+
+    pub fn status(&self) -> u8 {
+        match self {
+            MidiMsg::NoteOff { channel, .. } => 0x80 | channel.0,
+            MidiMsg::NoteOn { channel, .. } => 0x90 | channel.0,
+            MidiMsg::PolyPressure { channel, .. } => 0xA0 | channel.0,
+            MidiMsg::ControlChange { channel, .. } => 0xB0 | channel.0,
+            MidiMsg::ProgramChange { channel, .. } => 0xC0 | channel.0,
+            MidiMsg::ChannelPressure { channel, .. } => 0xD0 | channel.0,
+            MidiMsg::PitchBend { channel, .. } => 0xE0 | channel.0,
+            MidiMsg::SysEx(_) => 0xF0,
+            MidiMsg::TimeCodeQuarterFrame { .. } => 0xF1,
+            MidiMsg::SongPosition { .. } => 0xF2,
+            MidiMsg::SongSelect { .. } => 0xF3,
+            MidiMsg::TuneRequest => 0xF6,
+            MidiMsg::TimingClock => 0xF8,
+            MidiMsg::Start => 0xFA,
+            MidiMsg::Continue => 0xFB,
+            MidiMsg::Stop => 0xFC,
+            MidiMsg::ActiveSensing => 0xFE,
+            MidiMsg::SystemReset => 0xFF,
+        }
+    }
+    /// System real time message, may be sent between the bytes of any other one
+    pub fn is_realtime(&self) -> bool {
+        self.status() >= 0xF8
+    }
+    pub fn channel(&self) -> Option<Channel> {
+        match self {
+            MidiMsg::NoteOff { channel, .. }
+            | MidiMsg::NoteOn { channel, .. }
+            | MidiMsg::PolyPressure { channel, .. }
+            | MidiMsg::ControlChange { channel, .. }
+            | MidiMsg::ProgramChange { channel, .. }
+            | MidiMsg::ChannelPressure { channel, .. }
+            | MidiMsg::PitchBend { channel, .. } => Some(*channel),
+            _ => None,
+        }
+    }
+    /// Number of data bytes following the `status` byte, None for the start
+    /// of a SysEx (ended by F7) and for bytes which are no defined status
+    pub fn data_len(status: u8) -> Option<usize> {
+        match status {
+            0x80..=0xBF | 0xE0..=0xEF | 0xF2 => Some(2),
+            0xC0..=0xDF | 0xF1 | 0xF3 => Some(1),
+            0xF6 | 0xF8 | 0xFA..=0xFC | 0xFE | 0xFF => Some(0),
+            _ => None,
+        }
+    }
+    pub fn serialized_len(&self) -> usize {
+        match self {
+            MidiMsg::SysEx(sysex) => sysex.data().len() + 2,
+            _ => 1 + Self::data_len(self.status()).unwrap_or(0),
+        }
+    }
+    /// Write the message to `buf`, returns the number of bytes written,
+    /// 0 when it does not fit
     pub fn serialize(&self, buf: &mut [u8]) -> usize {
+        let len = self.serialized_len();
+        if buf.len() < len {
+            return 0;
+        }
+        buf[0] = self.status();
         match self {
-            MidiMsg::NoteOn { note, velocity } => {
-                if *note < 0 || *velocity < 0 || buf.len() < 3 {
-                    0
-                } else {
-                    buf[0] = 0x90u8;
-                    buf[1] = *note as u8;
-                    buf[2] = *velocity as u8;
-                    3
-                }
+            MidiMsg::NoteOn { note, velocity, .. } | MidiMsg::NoteOff { note, velocity, .. } => {
+                buf[1] = note.0;
+                buf[2] = velocity.0;
             }
-            MidiMsg::NoteOff { note, velocity } => {
-                if *note < 0 || *velocity < 0 || buf.len() < 3 {
-                    0
-                } else {
-                    buf[0] = 0x80u8;
-                    buf[1] = *note as u8;
-                    buf[2] = *velocity as u8;
-                    3
-                }
+            MidiMsg::PolyPressure { note, pressure, .. } => {
+                buf[1] = note.0;
+                buf[2] = pressure.0;
             }
+            MidiMsg::ControlChange { control, value, .. } => {
+                buf[1] = control.0;
+                buf[2] = value.0;
+            }
+            MidiMsg::ProgramChange { program: value, .. }
+            | MidiMsg::ChannelPressure {
+                pressure: value, ..
+            }
+            | MidiMsg::TimeCodeQuarterFrame { value }
+            | MidiMsg::SongSelect { song: value } => {
+                buf[1] = value.0;
+            }
+            MidiMsg::PitchBend { value, .. } | MidiMsg::SongPosition { beats: value } => {
+                buf[1] = value.lsb().0;
+                buf[2] = value.msb().0;
+            }
+            MidiMsg::SysEx(sysex) => {
+                buf[1..len - 1].copy_from_slice(sysex.data());
+                buf[len - 1] = 0xF7;
+            }
+            _ => (),
         }
+        len
+    }
+    /// Message of a `status` byte other than the SysEx start with its data
+    /// bytes, those not used by the message are ignored
+    pub fn from_parts(status: u8, data1: U7, data2: U7) -> Result<MidiMsg, &'static str> {
+        let channel = Channel(status & 0x0F);
+        let msg = match status & 0xF0 {
+            0x80 => MidiMsg::NoteOff {
+                channel,
+                note: data1,
+                velocity: data2,
+            },
+            0x90 => MidiMsg::NoteOn {
+                channel,
+                note: data1,
+                velocity: data2,
+            },
+            0xA0 => MidiMsg::PolyPressure {
+                channel,
+                note: data1,
+                pressure: data2,
+            },
+            0xB0 => MidiMsg::ControlChange {
+                channel,
+                control: data1,
+                value: data2,
+            },
+            0xC0 => MidiMsg::ProgramChange {
+                channel,
+                program: data1,
+            },
+            0xD0 => MidiMsg::ChannelPressure {
+                channel,
+                pressure: data1,
+            },
+            0xE0 => MidiMsg::PitchBend {
+                channel,
+                value: U14::from_bytes(data1, data2),
+            },
+            _ => match status {
+                0xF1 => MidiMsg::TimeCodeQuarterFrame { value: data1 },
+                0xF2 => MidiMsg::SongPosition {
+                    beats: U14::from_bytes(data1, data2),
+                },
+                0xF3 => MidiMsg::SongSelect { song: data1 },
+                0xF6 => MidiMsg::TuneRequest,
+                0xF8 => MidiMsg::TimingClock,
+                0xFA => MidiMsg::Start,
+                0xFB => MidiMsg::Continue,
+                0xFC => MidiMsg::Stop,
+                0xFE => MidiMsg::ActiveSensing,
+                0xFF => MidiMsg::SystemReset,
+                _ => return Err("not a status byte"),
+            },
+        };
+        Ok(msg)
+    }
+    /// Parse a single complete message as written by `serialize()`
+    #[allow(dead_code)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<MidiMsg, &'static str> {
+        let (&status, data) = bytes.split_first().ok_or("empty message")?;
+        if status == 0xF0 {
+            return match data.split_last() {
+                Some((0xF7, data)) => SysEx::new(data).map(MidiMsg::SysEx).ok_or("bad SysEx"),
+                _ => Err("unterminated SysEx"),
+            };
+        }
+        if Self::data_len(status) != Some(data.len()) {
+            return Err("bad message length");
+        }
+        let mut values = [U7::MIN; 2];
+        for (value, &byte) in values.iter_mut().zip(data) {
+            *value = U7::new(byte).ok_or("bad data byte")?;
+        }
+        Self::from_parts(status, values[0], values[1])
     }
 
+    #[allow(dead_code)]
     pub async fn send_bytes(
         &self,
         writer: &mut impl embedded_io_async::Write,
     ) -> Result<usize, &'static str> {
-        let mut buf = [0u8; 3];
+        let mut buf = [0u8; MidiMsg::MAX_LEN];
 
         let num_bytes = self.serialize(&mut buf);
 
@@ -52,20 +463,451 @@ impl MidiMsg {
         }
         Ok(num_bytes)
     }
+}
 
-    pub fn usb_cin(&self) -> u8 {
-        match self {
-            MidiMsg::NoteOff { .. } => 0x08,
-            MidiMsg::NoteOn { .. } => 0x09,
+/// Decoder of a MIDI byte stream (DIN input, the bytes of USB packets),
+/// fed a byte at a time.
+///
+/// Handles running status and real time bytes in the middle of other
+/// messages. Data bytes without a status, messages cut by another status
+/// and SysEx messages too long for `SysEx` are dropped.
+pub struct MidiParser {
+    // running status, None until a channel message status is seen again
+    status: Option<u8>,
+    data: [U7; 2],
+    count: usize,
+    // SysEx being received
+    sysex: Option<SysEx>,
+    sysex_overflow: bool,
+}
+
+impl Default for MidiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self {
+            status: None,
+            data: [U7::MIN; 2],
+            count: 0,
+            sysex: None,
+            sysex_overflow: false,
+        }
+    }
+    /// Forget any partial message, e.g. when the input was disconnected
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+    /// Feed the next byte, returns the message it completes
+    pub fn push(&mut self, byte: u8) -> Option<MidiMsg> {
+        match byte {
+            // real time, may come anywhere and does not change the state
+            0xF8..=0xFF => MidiMsg::from_parts(byte, U7::MIN, U7::MIN).ok(),
+            0xF0 => {
+                self.end_message();
+                self.sysex = Some(SysEx::default());
+                self.sysex_overflow = false;
+                None
+            }
+            0xF7 => {
+                let sysex = self.sysex.take();
+                self.end_message();
+                match sysex {
+                    Some(_) if self.sysex_overflow => {
+                        debug!("MIDI in: SysEx too long, dropped");
+                        None
+                    }
+                    Some(sysex) => Some(MidiMsg::SysEx(sysex)),
+                    None => None,
+                }
+            }
+            0x80..=0xF6 => {
+                self.end_message();
+                match MidiMsg::data_len(byte) {
+                    Some(0) => MidiMsg::from_parts(byte, U7::MIN, U7::MIN).ok(),
+                    // undefined status, ignore until the next one
+                    None => None,
+                    Some(_) => {
+                        self.status = Some(byte);
+                        None
+                    }
+                }
+            }
+            _ => self.push_data(U7(byte)),
+        }
+    }
+    fn push_data(&mut self, value: U7) -> Option<MidiMsg> {
+        if let Some(sysex) = &mut self.sysex {
+            if !sysex.push(value.0) {
+                self.sysex_overflow = true;
+            }
+            return None;
+        }
+        let Some(status) = self.status else {
+            debug!("MIDI in: data byte without status: {}", value);
+            return None;
+        };
+        self.data[self.count] = value;
+        self.count += 1;
+        if Some(self.count) != MidiMsg::data_len(status) {
+            return None;
+        }
+        self.count = 0;
+        if status >= 0xF0 {
+            // no running status for system common messages
+            self.status = None;
+        }
+        MidiMsg::from_parts(status, self.data[0], self.data[1]).ok()
+    }
+    // a status byte ends any message in progress, complete or not
+    fn end_message(&mut self) {
+        if self.count > 0 || self.sysex.is_some() {
+            debug!("MIDI in: incomplete message dropped");
         }
+        self.status = None;
+        self.count = 0;
+        self.sysex = None;
     }
 }
 
-pub type MidiChannel = Channel<NoopRawMutex, MidiMsg, MIDI_CHANNEL_SIZE>;
+pub type MidiChannel = channel::Channel<NoopRawMutex, MidiMsg, MIDI_CHANNEL_SIZE>;
 pub type MidiChannelReceiver<'ch> = Receiver<'ch, NoopRawMutex, MidiMsg, MIDI_CHANNEL_SIZE>;
+pub type MidiChannelSender<'ch> = Sender<'ch, NoopRawMutex, MidiMsg, MIDI_CHANNEL_SIZE>;
 
-pub type MidiChannelMC = Channel<CriticalSectionRawMutex, MidiMsg, MIDI_CHANNEL_SIZE>;
+pub type MidiChannelMC = channel::Channel<CriticalSectionRawMutex, MidiMsg, MIDI_CHANNEL_SIZE>;
 pub type MidiChannelMCReceiver<'ch> =
     Receiver<'ch, CriticalSectionRawMutex, MidiMsg, MIDI_CHANNEL_SIZE>;
 pub type MidiChannelMCSender<'ch> =
     Sender<'ch, CriticalSectionRawMutex, MidiMsg, MIDI_CHANNEL_SIZE>;
+
+/// Destination for MIDI messages
+#[allow(async_fn_in_trait)]
+pub trait MidiSink {
+    /// Send the message, waiting until there is room for it
+    async fn send(&self, msg: MidiMsg);
+    /// Send the message if there is room for it now, returns false if it was dropped
+    fn try_send(&self, msg: MidiMsg) -> bool;
+}
+
+impl<'ch, M: RawMutex> MidiSink for Sender<'ch, M, MidiMsg, MIDI_CHANNEL_SIZE> {
+    async fn send(&self, msg: MidiMsg) {
+        Sender::send(self, msg).await
+    }
+    fn try_send(&self, msg: MidiMsg) -> bool {
+        Sender::try_send(self, msg).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u7(value: u8) -> U7 {
+        U7::new(value).unwrap()
+    }
+
+    fn ch(index: u8) -> Channel {
+        Channel::new(index).unwrap()
+    }
+
+    fn serialized(msg: &MidiMsg) -> Vec<u8> {
+        let mut buf = [0; MidiMsg::MAX_LEN];
+        let len = msg.serialize(&mut buf);
+        buf[..len].to_vec()
+    }
+
+    /// A message of each kind and its bytes
+    fn all_messages() -> Vec<(MidiMsg, Vec<u8>)> {
+        vec![
+            (
+                MidiMsg::NoteOff {
+                    channel: ch(2),
+                    note: u7(60),
+                    velocity: u7(64),
+                },
+                vec![0x82, 60, 64],
+            ),
+            (
+                MidiMsg::NoteOn {
+                    channel: ch(0),
+                    note: u7(127),
+                    velocity: u7(0),
+                },
+                vec![0x90, 127, 0],
+            ),
+            (
+                MidiMsg::PolyPressure {
+                    channel: ch(15),
+                    note: u7(1),
+                    pressure: u7(2),
+                },
+                vec![0xAF, 1, 2],
+            ),
+            (
+                MidiMsg::ControlChange {
+                    channel: ch(9),
+                    control: u7(7),
+                    value: u7(127),
+                },
+                vec![0xB9, 7, 127],
+            ),
+            (
+                MidiMsg::ProgramChange {
+                    channel: ch(1),
+                    program: u7(5),
+                },
+                vec![0xC1, 5],
+            ),
+            (
+                MidiMsg::ChannelPressure {
+                    channel: ch(3),
+                    pressure: u7(99),
+                },
+                vec![0xD3, 99],
+            ),
+            (
+                MidiMsg::PitchBend {
+                    channel: ch(4),
+                    value: U14::new(0x2345).unwrap(),
+                },
+                vec![0xE4, 0x45, 0x46],
+            ),
+            (
+                MidiMsg::TimeCodeQuarterFrame { value: u7(0x35) },
+                vec![0xF1, 0x35],
+            ),
+            (
+                MidiMsg::SongPosition { beats: U14::MAX },
+                vec![0xF2, 0x7F, 0x7F],
+            ),
+            (MidiMsg::SongSelect { song: u7(3) }, vec![0xF3, 3]),
+            (MidiMsg::TuneRequest, vec![0xF6]),
+            (
+                MidiMsg::SysEx(SysEx::new(&[0x7E, 0x7F, 0x06, 0x01]).unwrap()),
+                vec![0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7],
+            ),
+            (MidiMsg::SysEx(SysEx::default()), vec![0xF0, 0xF7]),
+            (MidiMsg::TimingClock, vec![0xF8]),
+            (MidiMsg::Start, vec![0xFA]),
+            (MidiMsg::Continue, vec![0xFB]),
+            (MidiMsg::Stop, vec![0xFC]),
+            (MidiMsg::ActiveSensing, vec![0xFE]),
+            (MidiMsg::SystemReset, vec![0xFF]),
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for (msg, bytes) in all_messages() {
+            assert_eq!(serialized(&msg), bytes);
+            assert_eq!(msg.serialized_len(), bytes.len());
+            assert_eq!(msg.status(), bytes[0]);
+            assert!(MidiMsg::from_bytes(&bytes) == Ok(msg), "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn message_classes() {
+        for (msg, bytes) in all_messages() {
+            assert_eq!(msg.is_realtime(), bytes[0] >= 0xF8);
+            let channel = msg.channel().map(Channel::index);
+            assert_eq!(channel, (bytes[0] < 0xF0).then_some(bytes[0] & 0x0F));
+        }
+    }
+
+    #[test]
+    fn serialize_needs_room() {
+        let msg = MidiMsg::from_bytes(&[0x90, 60, 100]).unwrap();
+        assert_eq!(msg.serialize(&mut [0; 2]), 0);
+        assert_eq!(msg.serialize(&mut [0; 3]), 3);
+        let sysex = MidiMsg::SysEx(SysEx::new(&[0; SYSEX_MAX_LEN]).unwrap());
+        assert_eq!(sysex.serialize(&mut [0; MidiMsg::MAX_LEN - 1]), 0);
+        assert_eq!(
+            sysex.serialize(&mut [0; MidiMsg::MAX_LEN]),
+            MidiMsg::MAX_LEN
+        );
+    }
+
+    #[test]
+    fn malformed_bytes_are_rejected() {
+        assert!(MidiMsg::from_bytes(&[]) == Err("empty message"));
+        assert!(MidiMsg::from_bytes(&[0x90, 60]) == Err("bad message length"));
+        assert!(MidiMsg::from_bytes(&[0xF8, 0]) == Err("bad message length"));
+        assert!(MidiMsg::from_bytes(&[0x90, 60, 0x80]) == Err("bad data byte"));
+        assert!(MidiMsg::from_bytes(&[0x3C, 60, 100]) == Err("bad message length"));
+        assert!(MidiMsg::from_bytes(&[0xF4]) == Err("bad message length"));
+        assert!(MidiMsg::from_bytes(&[0xF0, 1, 2]) == Err("unterminated SysEx"));
+        assert!(MidiMsg::from_bytes(&[0xF0, 0x80, 0xF7]) == Err("bad SysEx"));
+        assert!(MidiMsg::from_parts(0xF7, U7::MIN, U7::MIN) == Err("not a status byte"));
+    }
+
+    #[test]
+    fn u7_range() {
+        assert!(U7::new(0) == Some(U7::MIN));
+        assert!(U7::new(127) == Some(U7::MAX));
+        assert!(U7::new(128).is_none());
+        assert!(U7::new(255).is_none());
+        assert!(U7::saturating(-1) == U7::MIN);
+        assert_eq!(U7::saturating(64).get(), 64);
+        assert!(U7::saturating(1000) == U7::MAX);
+        assert!(u7(120).checked_add(7) == Some(U7::MAX));
+        assert!(u7(120).checked_add(8).is_none());
+        assert!(u7(5).checked_add(-5) == Some(U7::MIN));
+        assert!(u7(5).checked_add(-6).is_none());
+        assert!(U7::try_from(127i8) == Ok(U7::MAX));
+        assert!(U7::try_from(-1i8) == Err("negative 7-bit value"));
+        assert_eq!(u8::from(u7(42)), 42);
+    }
+
+    #[test]
+    fn u14_range() {
+        assert!(U14::new(0) == Some(U14::MIN));
+        assert!(U14::new(0x3FFF) == Some(U14::MAX));
+        assert!(U14::new(0x4000).is_none());
+        assert!(U14::saturating(-1) == U14::MIN);
+        assert!(U14::saturating(0x4000) == U14::MAX);
+        assert!(U14::from_signed(0) == U14::CENTER);
+        assert!(U14::from_signed(-8192) == U14::MIN);
+        assert!(U14::from_signed(8191) == U14::MAX);
+        assert!(U14::from_signed(-10000) == U14::MIN);
+        assert!(U14::from_signed(10000) == U14::MAX);
+        assert_eq!(U14::MIN.signed(), -8192);
+        assert_eq!(U14::MAX.signed(), 8191);
+        let value = U14::new(0x1234).unwrap();
+        assert_eq!((value.lsb().get(), value.msb().get()), (0x34, 0x24));
+        assert!(U14::from_bytes(value.lsb(), value.msb()) == value);
+    }
+
+    #[test]
+    fn channel_range() {
+        assert!(Channel::new(0).map(Channel::index) == Some(0));
+        assert!(Channel::new(15).map(Channel::index) == Some(15));
+        assert!(Channel::new(16).is_none());
+        assert_eq!(Channel::saturating(200).index(), 15);
+    }
+
+    #[test]
+    fn sysex_accepts_7_bit_data_up_to_the_limit() {
+        assert!(SysEx::new(&[0; SYSEX_MAX_LEN]).is_some());
+        assert!(SysEx::new(&[0; SYSEX_MAX_LEN + 1]).is_none());
+        assert!(SysEx::new(&[0x7F, 0x80]).is_none());
+        let mut sysex = SysEx::default();
+        assert!(sysex.push(1));
+        assert!(!sysex.push(0xF7));
+        assert_eq!(sysex.data(), [1]);
+    }
+
+    fn parse(bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut parser = MidiParser::new();
+        bytes
+            .iter()
+            .filter_map(|&byte| parser.push(byte))
+            .map(|msg| serialized(&msg))
+            .collect()
+    }
+
+    #[test]
+    fn parser_follows_running_status() {
+        assert_eq!(
+            parse(&[0x90, 60, 100, 61, 0, 0xB1, 7, 1, 7, 2]),
+            [
+                vec![0x90, 60, 100],
+                vec![0x90, 61, 0],
+                vec![0xB1, 7, 1],
+                vec![0xB1, 7, 2]
+            ]
+        );
+        assert_eq!(parse(&[0xC0, 1, 2, 3]), [[0xC0, 1], [0xC0, 2], [0xC0, 3]]);
+    }
+
+    #[test]
+    fn parser_passes_realtime_through() {
+        // in the middle of a channel message, running status kept
+        assert_eq!(
+            parse(&[0x90, 60, 0xF8, 100, 0xFE, 61, 0xFA, 101]),
+            [
+                vec![0xF8],
+                vec![0x90, 60, 100],
+                vec![0xFE],
+                vec![0xFA],
+                vec![0x90, 61, 101]
+            ]
+        );
+        // in the middle of a SysEx
+        assert_eq!(
+            parse(&[0xF0, 1, 0xF8, 2, 0xFC, 0xF7]),
+            [vec![0xF8], vec![0xFC], vec![0xF0, 1, 2, 0xF7]]
+        );
+    }
+
+    #[test]
+    fn parser_collects_sysex() {
+        assert_eq!(parse(&[0xF0, 0xF7]), [[0xF0, 0xF7]]);
+        let mut bytes = vec![0xF0];
+        bytes.extend((0..SYSEX_MAX_LEN as u8).map(|i| i + 1));
+        bytes.push(0xF7);
+        assert_eq!(parse(&bytes), [bytes.clone()]);
+        // split over several pushes of the same parser, e.g. USB packets
+        let mut parser = MidiParser::new();
+        for &byte in &bytes[..bytes.len() - 1] {
+            assert!(parser.push(byte).is_none());
+        }
+        assert!(parser.push(0xF7) == MidiMsg::from_bytes(&bytes).ok());
+        // no running status after it
+        assert!(parser.push(1).is_none());
+        // an end without a start
+        assert!(parse(&[0xF7, 1, 2]).is_empty());
+    }
+
+    #[test]
+    fn parser_drops_sysex_overflow() {
+        let mut bytes = vec![0xF0];
+        bytes.extend([1; SYSEX_MAX_LEN + 1]);
+        bytes.extend([0xF7, 0x90, 60, 100]);
+        assert_eq!(parse(&bytes), [[0x90, 60, 100]]);
+        // the next one is not affected
+        bytes.extend([0xF0, 5, 0xF7]);
+        assert_eq!(parse(&bytes), [vec![0x90, 60, 100], vec![0xF0, 5, 0xF7]]);
+    }
+
+    #[test]
+    fn parser_skips_data_without_status() {
+        assert_eq!(parse(&[1, 2, 3, 0x90, 60, 100]), [[0x90, 60, 100]]);
+        // no running status for system common messages
+        assert_eq!(
+            parse(&[0xF3, 1, 2, 0xF2, 1, 2, 3, 4]),
+            [vec![0xF3, 1], vec![0xF2, 1, 2]]
+        );
+        assert_eq!(
+            parse(&[0x90, 60, 100, 0xF6, 61, 100]),
+            [vec![0x90, 60, 100], vec![0xF6]]
+        );
+        // nor after an undefined status
+        assert!(parse(&[0x90, 0xF4, 60, 100, 0xF5, 1]).is_empty());
+    }
+
+    #[test]
+    fn parser_drops_message_cut_by_status() {
+        assert_eq!(parse(&[0x90, 60, 0xB0, 7, 100]), [[0xB0, 7, 100]]);
+        assert_eq!(parse(&[0xE0, 0, 0xF0, 1, 0xF7]), [[0xF0, 1, 0xF7]]);
+        // a SysEx cut by a status byte
+        assert_eq!(parse(&[0xF0, 1, 2, 0x80, 60, 0]), [[0x80, 60, 0]]);
+        assert_eq!(parse(&[0xF0, 1, 2, 0xF0, 3, 0xF7]), [[0xF0, 3, 0xF7]]);
+    }
+
+    #[test]
+    fn parser_reset_forgets_partial_message() {
+        let mut parser = MidiParser::new();
+        assert!(parser.push(0x90).is_none());
+        assert!(parser.push(60).is_none());
+        parser.reset();
+        assert!(parser.push(100).is_none());
+        assert!(parser.push(60).is_none());
+        assert!(parser.push(0xF0).is_none());
+        parser.reset();
+        assert!(parser.push(0xF7).is_none());
+    }
+}
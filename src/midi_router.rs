@@ -0,0 +1,329 @@
+// Routing of the MIDI messages from core 0 to the outputs: each output keeps
+// track of the notes sounding there, so none of them is left hanging when
+// the output has no room for a note-off or gets disconnected
+
+use core::cell::Cell;
+
+use defmt::{debug, info};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+
+use crate::midi::{Channel, MidiMsg, MidiSink, U7};
+
+/// Connection state changes of an output. The changes are counted, so a
+/// disconnection is not missed when the output is back before the router looks.
+pub struct ConnectionSignal {
+    // number of changes so far, odd while connected
+    changes: Cell<u32>,
+    // changes the router has gone through
+    seen: Cell<u32>,
+    signal: Signal<NoopRawMutex, ()>,
+}
+
+impl Default for ConnectionSignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionSignal {
+    pub const fn new() -> Self {
+        Self {
+            changes: Cell::new(0),
+            seen: Cell::new(0),
+            signal: Signal::new(),
+        }
+    }
+    /// The output is connected or not, repeating the current state is no change
+    pub fn signal(&self, connected: bool) {
+        let changes = self.changes.get();
+        if (changes % 2 == 1) != connected {
+            self.changes.set(changes.wrapping_add(1));
+            self.signal.signal(());
+        }
+    }
+    /// Wait for changes, returns the states to go through: a disconnection
+    /// in between, then the current state
+    pub async fn wait(&self) -> [Option<bool>; 2] {
+        while self.changes.get() == self.seen.get() {
+            self.signal.wait().await;
+        }
+        let changes = self.changes.get();
+        let missed = changes.wrapping_sub(self.seen.get()) > 1;
+        self.seen.set(changes);
+        let connected = changes % 2 == 1;
+        [(missed && connected).then_some(false), Some(connected)]
+    }
+}
+
+// channel, note number and whether it is a note-on
+fn note_key(msg: &MidiMsg) -> Option<(Channel, U7, bool)> {
+    match *msg {
+        MidiMsg::NoteOn {
+            channel,
+            note,
+            velocity,
+        } => Some((channel, note, velocity != U7::MIN)),
+        MidiMsg::NoteOff { channel, note, .. } => Some((channel, note, false)),
+        _ => None,
+    }
+}
+
+/// A single output with its notes
+pub struct OutputRoute<S: MidiSink> {
+    name: &'static str,
+    sink: S,
+    connected: bool,
+    // bit per note, per channel: sounding at the output
+    active: [u128; 16],
+    // note-offs not delivered yet
+    pending_offs: [u128; 16],
+    dropped: u32,
+    // dropped at the last report
+    reported: u32,
+}
+
+impl<S: MidiSink> OutputRoute<S> {
+    pub fn new(name: &'static str, sink: S, connected: bool) -> Self {
+        Self {
+            name,
+            sink,
+            connected,
+            active: [0; 16],
+            pending_offs: [0; 16],
+            dropped: 0,
+            reported: 0,
+        }
+    }
+    /// Log the number of messages lost since the start, if it changed since
+    /// the last report
+    pub fn report_dropped(&mut self) {
+        if self.dropped != self.reported {
+            info!("{}: {} messages dropped", self.name, self.dropped);
+            self.reported = self.dropped;
+        }
+    }
+    pub fn has_pending(&self) -> bool {
+        self.connected && self.pending_offs.iter().any(|offs| *offs != 0)
+    }
+    fn drop_msg(&mut self, msg: &MidiMsg) {
+        self.dropped = self.dropped.wrapping_add(1);
+        debug!("{}: dropped {} ({} so far)", self.name, msg, self.dropped);
+    }
+    /// Send the pending note-offs there is room for
+    pub fn flush(&mut self) {
+        if !self.connected {
+            return;
+        }
+        for (index, offs) in self.pending_offs.iter_mut().enumerate() {
+            while *offs != 0 {
+                let note = offs.trailing_zeros();
+                let msg = MidiMsg::NoteOff {
+                    channel: Channel::saturating(index as u8),
+                    note: U7::saturating(note as i32),
+                    velocity: U7::MIN,
+                };
+                if !self.sink.try_send(msg) {
+                    return;
+                }
+                debug!("{}: note-off re-sent: {}", self.name, msg);
+                *offs &= !(1 << note);
+            }
+        }
+    }
+    /// The output was connected or disconnected, the notes sounding at the
+    /// time of a disconnection get note-offs when it comes back
+    pub fn set_connected(&mut self, connected: bool) {
+        if connected == self.connected {
+            return;
+        }
+        info!("{}: connected: {}", self.name, connected);
+        for (offs, active) in self.pending_offs.iter_mut().zip(self.active.iter_mut()) {
+            *offs |= *active;
+            *active = 0;
+        }
+        self.connected = connected;
+        self.flush();
+    }
+    /// Pass `msg` to the output, if there is room for it
+    pub fn route(&mut self, msg: MidiMsg) {
+        if !self.connected {
+            self.drop_msg(&msg);
+            return;
+        }
+        // the older note-offs go first
+        self.flush();
+        let Some((channel, note, on)) = note_key(&msg) else {
+            if !self.sink.try_send(msg) {
+                self.drop_msg(&msg);
+            }
+            return;
+        };
+        let index = channel.index() as usize;
+        let bit = 1u128 << note.get();
+        if on {
+            // not before the note-off of the previous one
+            if self.pending_offs[index] & bit != 0 {
+                self.drop_msg(&msg);
+            } else if self.sink.try_send(msg) {
+                self.active[index] |= bit;
+            } else {
+                self.drop_msg(&msg);
+            }
+        } else if self.sink.try_send(msg) {
+            self.active[index] &= !bit;
+            self.pending_offs[index] &= !bit;
+        } else if self.active[index] & bit != 0 {
+            // sent later, as soon as there is room
+            self.active[index] &= !bit;
+            self.pending_offs[index] |= bit;
+        } else {
+            self.drop_msg(&msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    /// Output with room for `room` more messages
+    struct TestSink {
+        room: Cell<usize>,
+        sent: RefCell<Vec<MidiMsg>>,
+    }
+
+    impl TestSink {
+        fn new(room: usize) -> Self {
+            Self {
+                room: Cell::new(room),
+                sent: RefCell::new(Vec::new()),
+            }
+        }
+        fn take(&self) -> Vec<MidiMsg> {
+            self.sent.take()
+        }
+    }
+
+    impl MidiSink for &TestSink {
+        async fn send(&self, msg: MidiMsg) {
+            self.sent.borrow_mut().push(msg);
+        }
+        fn try_send(&self, msg: MidiMsg) -> bool {
+            if self.room.get() == 0 {
+                return false;
+            }
+            self.room.set(self.room.get() - 1);
+            self.sent.borrow_mut().push(msg);
+            true
+        }
+    }
+
+    fn note_on(note: u8) -> MidiMsg {
+        MidiMsg::from_bytes(&[0x90, note, 100]).unwrap()
+    }
+
+    fn note_off(note: u8) -> MidiMsg {
+        MidiMsg::from_bytes(&[0x80, note, 0]).unwrap()
+    }
+
+    #[test]
+    fn note_off_waits_for_room() {
+        let sink = TestSink::new(2);
+        let mut route = OutputRoute::new("test", &sink, true);
+        route.route(note_on(60));
+        route.route(note_on(61));
+        assert!(sink.take() == [note_on(60), note_on(61)]);
+        // no room: the note-off is kept, the others are dropped
+        route.route(note_off(60));
+        route.route(MidiMsg::TimingClock);
+        assert!(route.has_pending());
+        // not before the note-off of the previous one
+        route.route(note_on(60));
+        // not sounding, nothing to keep
+        route.route(note_off(62));
+        assert_eq!(route.dropped, 3);
+        assert!(sink.take().is_empty());
+
+        sink.room.set(10);
+        route.flush();
+        assert!(!route.has_pending());
+        route.route(note_on(62));
+        assert!(sink.take() == [note_off(60), note_on(62)]);
+    }
+
+    #[test]
+    fn notes_sounding_at_disconnection_are_stopped() {
+        let sink = TestSink::new(10);
+        let mut route = OutputRoute::new("test", &sink, true);
+        route.route(note_on(60));
+        route.route(note_on(61));
+        route.route(note_off(61));
+        sink.take();
+        route.set_connected(false);
+        route.route(note_on(62));
+        route.route(note_off(60));
+        assert_eq!(route.dropped, 2);
+        assert!(!route.has_pending());
+        assert!(sink.take().is_empty());
+        route.set_connected(true);
+        assert!(sink.take() == [note_off(60)]);
+        // all stopped, nothing more the next time
+        route.set_connected(false);
+        route.set_connected(true);
+        assert!(sink.take().is_empty());
+    }
+
+    #[test]
+    fn dropped_count_reported_when_changed() {
+        let sink = TestSink::new(0);
+        let mut route = OutputRoute::new("test", &sink, false);
+        route.report_dropped();
+        assert_eq!(route.reported, 0);
+        route.route(note_on(60));
+        route.report_dropped();
+        assert_eq!(route.reported, 1);
+    }
+
+    #[test]
+    fn connection_changes() {
+        let connection = ConnectionSignal::new();
+        connection.signal(false);
+        connection.signal(true);
+        assert_eq!(block_on(connection.wait()), [None, Some(true)]);
+        connection.signal(true);
+        connection.signal(false);
+        assert_eq!(block_on(connection.wait()), [None, Some(false)]);
+        // back before the router looked
+        connection.signal(true);
+        connection.signal(false);
+        connection.signal(true);
+        assert_eq!(block_on(connection.wait()), [Some(false), Some(true)]);
+        connection.signal(false);
+        connection.signal(true);
+        connection.signal(false);
+        assert_eq!(block_on(connection.wait()), [None, Some(false)]);
+    }
+
+    #[test]
+    fn quick_reconnection_stops_the_notes() {
+        let sink = TestSink::new(10);
+        let connection = ConnectionSignal::new();
+        connection.signal(true);
+        let mut route = OutputRoute::new("test", &sink, false);
+        for connected in block_on(connection.wait()).into_iter().flatten() {
+            route.set_connected(connected);
+        }
+        route.route(note_on(60));
+        connection.signal(false);
+        connection.signal(true);
+        for connected in block_on(connection.wait()).into_iter().flatten() {
+            route.set_connected(connected);
+        }
+        assert!(sink.take() == [note_on(60), note_off(60)]);
+    }
+}
@@ -0,0 +1,619 @@
+// MPR121 capacitive touch controller, an alternative to the RC sensors
+//
+// The driver only needs an `embedded_hal_async` I2C bus and interrupt pin,
+// so it is also tested against a mock bus on the host.
+
+use defmt::{info, warn};
+use embassy_futures::select::select;
+use embassy_time::{Duration, Timer};
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::i2c::I2c;
+
+use crate::config::*;
+use crate::touch_sensors::{CalibrationDataSet, CalibrationStatus, TouchSensorStatus};
+use crate::touch_source::TouchSource;
+
+pub const ELECTRODES: usize = 12;
+
+// registers
+const REG_TOUCH_STATUS: u8 = 0x00;
+const REG_FILTERED_DATA: u8 = 0x04;
+const REG_BASELINE: u8 = 0x1E;
+const REG_MHD_RISING: u8 = 0x2B;
+const REG_TOUCH_THRESHOLD: u8 = 0x41;
+const REG_DEBOUNCE: u8 = 0x5B;
+const REG_CONFIG1: u8 = 0x5C;
+const REG_CONFIG2: u8 = 0x5D;
+const REG_ECR: u8 = 0x5E;
+const REG_SOFT_RESET: u8 = 0x80;
+
+const SOFT_RESET: u8 = 0x63;
+// over current flag in the touch status
+const STATUS_OVCF: u16 = 0x8000;
+// baseline tracking enabled, initial baseline from the first readings
+const ECR_BASELINE_INIT: u8 = 0b10 << 6;
+// baseline tracking enabled, continuing from the current baseline (CL = 0b00)
+const ECR_BASELINE_KEEP: u8 = 0;
+
+/// Baseline filter settings for one direction of the data change,
+/// see the MPR121 datasheet / AN3891
+#[derive(Clone, Copy)]
+pub struct BaselineFilter {
+    // max half delta
+    pub mhd: u8,
+    // noise half delta
+    pub nhd: u8,
+    // noise count limit
+    pub ncl: u8,
+    // filter delay count limit
+    pub fdl: u8,
+}
+
+#[derive(Clone, Copy)]
+pub struct Mpr121Config {
+    pub rising: BaselineFilter,
+    pub falling: BaselineFilter,
+    // the touched direction, no max half delta
+    pub touched: BaselineFilter,
+    pub touch_threshold: u8,
+    pub release_threshold: u8,
+    // debounce counts, release in bits 6..4, touch in bits 2..0
+    pub debounce: u8,
+    // first filter iterations and charge current
+    pub config1: u8,
+    // charge time, second filter iterations and sample interval
+    pub config2: u8,
+    pub electrodes: u8,
+}
+
+impl Default for Mpr121Config {
+    fn default() -> Self {
+        Self {
+            rising: BaselineFilter {
+                mhd: 0x01,
+                nhd: 0x01,
+                ncl: 0x0E,
+                fdl: 0x00,
+            },
+            falling: BaselineFilter {
+                mhd: 0x01,
+                nhd: 0x05,
+                ncl: 0x01,
+                fdl: 0x00,
+            },
+            touched: BaselineFilter {
+                mhd: 0x00,
+                nhd: 0x00,
+                ncl: 0x00,
+                fdl: 0x00,
+            },
+            touch_threshold: 12,
+            release_threshold: 6,
+            debounce: 0x00,
+            // 6 samples, 16uA
+            config1: 0x10,
+            // 0.5us, 4 samples, 1ms
+            config2: 0x20,
+            electrodes: ELECTRODES as u8,
+        }
+    }
+}
+
+/// Register level access to the MPR121
+pub struct Mpr121<I: I2c> {
+    i2c: I,
+    address: u8,
+    config: Mpr121Config,
+}
+
+impl<I: I2c> Mpr121<I> {
+    pub fn new(i2c: I, address: u8) -> Self {
+        Self {
+            i2c,
+            address,
+            config: Mpr121Config::default(),
+        }
+    }
+
+    async fn write_reg(&mut self, reg: u8, value: u8) -> Result<(), &'static str> {
+        self.i2c
+            .write(self.address, &[reg, value])
+            .await
+            .map_err(|_| "I2C write failed")
+    }
+
+    async fn read_regs(&mut self, reg: u8, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.i2c
+            .write_read(self.address, &[reg], buf)
+            .await
+            .map_err(|_| "I2C read failed")
+    }
+
+    /// Reset the chip and configure it, the electrodes are running afterwards
+    pub async fn init(&mut self, config: Mpr121Config) -> Result<(), &'static str> {
+        self.config = config;
+        self.write_reg(REG_SOFT_RESET, SOFT_RESET).await?;
+        // after reset CONFIG2 reads 0x24, anything else is not an MPR121
+        let mut config2 = [0];
+        self.read_regs(REG_CONFIG2, &mut config2).await?;
+        if config2[0] != 0x24 {
+            return Err("MPR121 not found");
+        }
+        self.stop().await?;
+
+        let filters = [config.rising, config.falling];
+        for (i, f) in filters.iter().enumerate() {
+            let reg = REG_MHD_RISING + 4 * i as u8;
+            self.write_reg(reg, f.mhd).await?;
+            self.write_reg(reg + 1, f.nhd).await?;
+            self.write_reg(reg + 2, f.ncl).await?;
+            self.write_reg(reg + 3, f.fdl).await?;
+        }
+        // the touched filter has no MHD register
+        self.write_reg(REG_MHD_RISING + 8, config.touched.nhd)
+            .await?;
+        self.write_reg(REG_MHD_RISING + 9, config.touched.ncl)
+            .await?;
+        self.write_reg(REG_MHD_RISING + 10, config.touched.fdl)
+            .await?;
+
+        self.write_thresholds(config.touch_threshold, config.release_threshold)
+            .await?;
+        self.write_reg(REG_DEBOUNCE, config.debounce).await?;
+        self.write_reg(REG_CONFIG1, config.config1).await?;
+        self.write_reg(REG_CONFIG2, config.config2).await?;
+        self.run_electrodes(ECR_BASELINE_INIT).await
+    }
+
+    /// Stop mode, required for changing the configuration
+    pub async fn stop(&mut self) -> Result<(), &'static str> {
+        self.write_reg(REG_ECR, 0).await
+    }
+
+    /// Run mode again after `stop()`, keeping the baselines tracked so far
+    pub async fn start(&mut self) -> Result<(), &'static str> {
+        self.run_electrodes(ECR_BASELINE_KEEP).await
+    }
+
+    async fn run_electrodes(&mut self, baseline: u8) -> Result<(), &'static str> {
+        let electrodes = self.config.electrodes.min(ELECTRODES as u8);
+        self.write_reg(REG_ECR, baseline | electrodes).await
+    }
+
+    async fn write_thresholds(&mut self, touch: u8, release: u8) -> Result<(), &'static str> {
+        for i in 0..ELECTRODES as u8 {
+            self.write_reg(REG_TOUCH_THRESHOLD + 2 * i, touch).await?;
+            self.write_reg(REG_TOUCH_THRESHOLD + 2 * i + 1, release)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Change the touch and release thresholds of all the electrodes
+    pub async fn set_thresholds(&mut self, touch: u8, release: u8) -> Result<(), &'static str> {
+        self.stop().await?;
+        self.write_thresholds(touch, release).await?;
+        self.config.touch_threshold = touch;
+        self.config.release_threshold = release;
+        self.start().await
+    }
+
+    /// Touch status bits of the electrodes (0..11), the over current flag in bit 15
+    pub async fn touch_status(&mut self) -> Result<u16, &'static str> {
+        let mut buf = [0; 2];
+        self.read_regs(REG_TOUCH_STATUS, &mut buf).await?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    /// 10-bit filtered electrode data
+    pub async fn filtered_data(&mut self) -> Result<[u16; ELECTRODES], &'static str> {
+        let mut buf = [0; 2 * ELECTRODES];
+        self.read_regs(REG_FILTERED_DATA, &mut buf).await?;
+        Ok(core::array::from_fn(|i| {
+            u16::from_le_bytes([buf[2 * i], buf[2 * i + 1]]) & 0x3FF
+        }))
+    }
+
+    /// Electrode baselines, in the filtered data scale
+    pub async fn baselines(&mut self) -> Result<[u16; ELECTRODES], &'static str> {
+        let mut buf = [0; ELECTRODES];
+        self.read_regs(REG_BASELINE, &mut buf).await?;
+        // only the 8 high bits are stored
+        Ok(core::array::from_fn(|i| (buf[i] as u16) << 2))
+    }
+
+    pub fn config(&self) -> &Mpr121Config {
+        &self.config
+    }
+}
+
+/// Touch source driven by the MPR121, electrode `i` is sensor `i`,
+/// the remaining sensors are not available
+pub struct Mpr121TouchSource<I: I2c, Q: Wait> {
+    dev: Mpr121<I>,
+    irq: Q,
+    ok: bool,
+    analog: bool,
+    // touch threshold wanted by the sensitivity, applied in the next scan
+    touch_threshold: u8,
+    status: [TouchSensorStatus; NUM_SENSORS],
+    levels: [u32; NUM_SENSORS],
+}
+
+impl<I: I2c, Q: Wait> Mpr121TouchSource<I, Q> {
+    pub fn new(i2c: I, irq: Q) -> Self {
+        let dev = Mpr121::new(i2c, MPR121_ADDRESS);
+        let touch_threshold = dev.config().touch_threshold;
+        Self {
+            dev,
+            irq,
+            ok: false,
+            analog: false,
+            touch_threshold,
+            status: [TouchSensorStatus::NA; NUM_SENSORS],
+            levels: [0; NUM_SENSORS],
+        }
+    }
+
+    async fn init(&mut self) {
+        let config = Mpr121Config {
+            touch_threshold: self.touch_threshold,
+            release_threshold: release_threshold(self.touch_threshold),
+            ..Default::default()
+        };
+        self.ok = match self.dev.init(config).await {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("MPR121: {}", e);
+                false
+            }
+        };
+    }
+
+    // the chip tracks the baselines itself, so only check the electrodes work
+    async fn calibration(&mut self) -> CalibrationDataSet {
+        let mut calibration = CalibrationDataSet::default();
+        let data = if self.ok {
+            self.dev.filtered_data().await.ok()
+        } else {
+            None
+        };
+        let Some(data) = data else {
+            calibration.status = CalibrationStatus::Bad;
+            return calibration;
+        };
+        for (i, pin_c) in calibration.pins.iter_mut().enumerate() {
+            pin_c.status = match data.get(i) {
+                // an open or shorted electrode reads at the ends of the range
+                Some(&value) if value > 0 && value < 0x3FF => CalibrationStatus::Ok,
+                _ => CalibrationStatus::Bad,
+            };
+        }
+        calibration.status = CalibrationStatus::Ok;
+        calibration.all.status = CalibrationStatus::Ok;
+        calibration
+    }
+
+    async fn read(&mut self) -> Result<(), &'static str> {
+        let config = self.dev.config();
+        if config.touch_threshold != self.touch_threshold {
+            let touch = self.touch_threshold;
+            self.dev
+                .set_thresholds(touch, release_threshold(touch))
+                .await?;
+        }
+        let touched = self.dev.touch_status().await?;
+        if touched & STATUS_OVCF != 0 {
+            return Err("MPR121 over current");
+        }
+        if self.analog {
+            let data = self.dev.filtered_data().await?;
+            let baselines = self.dev.baselines().await?;
+            for i in 0..ELECTRODES {
+                // touching lowers the data below the baseline
+                let delta = baselines[i].saturating_sub(data[i]) as u32;
+                self.levels[i] = (delta * 1000 / MPR121_FULL_LEVEL).min(1000);
+            }
+        }
+        for (i, status) in self.status.iter_mut().enumerate().take(ELECTRODES) {
+            *status = if touched & (1 << i) != 0 {
+                TouchSensorStatus::On
+            } else {
+                TouchSensorStatus::Off
+            };
+        }
+        Ok(())
+    }
+}
+
+fn release_threshold(touch: u8) -> u8 {
+    (touch as u32 * MPR121_RELEASE_PERCENT as u32 / 100) as u8
+}
+
+impl<I: I2c, Q: Wait> TouchSource for Mpr121TouchSource<I, Q> {
+    async fn calibrate_start(&mut self) {
+        self.init().await;
+    }
+    async fn calibrate_step(&mut self) -> CalibrationDataSet {
+        self.calibration().await
+    }
+    async fn calibrate_stop(&mut self) -> CalibrationDataSet {
+        let calibration = self.calibration().await;
+        info!("MPR121 calibration: {}", calibration);
+        calibration
+    }
+    async fn calibrate_idle(&mut self, duration: Duration) -> CalibrationDataSet {
+        self.init().await;
+        // let the baselines settle
+        Timer::after(duration).await;
+        self.calibrate_stop().await
+    }
+    fn set_sensitivity(&mut self, permile: u32) {
+        let range = (MPR121_TOUCH_THRESHOLD_MAX - MPR121_TOUCH_THRESHOLD_MIN) as u32;
+        let touch = MPR121_TOUCH_THRESHOLD_MAX - (range * permile.min(1000) / 1000) as u8;
+        // a noisy knob must not keep restarting the chip, the ends are always reachable
+        if touch.abs_diff(self.touch_threshold) > MPR121_THRESHOLD_DEAD_BAND
+            || touch == MPR121_TOUCH_THRESHOLD_MIN
+            || touch == MPR121_TOUCH_THRESHOLD_MAX
+        {
+            self.touch_threshold = touch;
+        }
+    }
+    fn set_analog(&mut self, analog: bool) {
+        self.analog = analog;
+    }
+    async fn run(&mut self) -> [TouchSensorStatus; NUM_SENSORS] {
+        if !self.ok {
+            return [TouchSensorStatus::NA; NUM_SENSORS];
+        }
+        // the IRQ pin goes low on touch status changes
+        select(self.irq.wait_for_low(), Timer::after(MPR121_POLL_INTERVAL)).await;
+        if let Err(e) = self.read().await {
+            warn!("{}", e);
+            return [TouchSensorStatus::NA; NUM_SENSORS];
+        }
+        self.status
+    }
+    fn levels(&self) -> [u32; NUM_SENSORS] {
+        if self.analog {
+            self.levels
+        } else {
+            [0; NUM_SENSORS]
+        }
+    }
+    fn threshold_level(&self, _i: usize) -> u32 {
+        (self.touch_threshold as u32 * 1000 / MPR121_FULL_LEVEL).min(1000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use embassy_futures::block_on;
+    use embedded_hal_async::i2c::{ErrorType, Operation};
+
+    use super::*;
+
+    /// Register file of an MPR121, with the writes logged
+    struct MockBus {
+        regs: [u8; 0x81],
+        // the register pointer, auto-incremented by the reads and writes
+        pointer: usize,
+        writes: Vec<(u8, u8)>,
+        // answers like an MPR121 after a reset
+        present: bool,
+    }
+
+    impl MockBus {
+        fn new() -> Self {
+            Self {
+                regs: [0; 0x81],
+                pointer: 0,
+                writes: Vec::new(),
+                present: true,
+            }
+        }
+        fn ecr_writes(&self) -> Vec<u8> {
+            self.writes
+                .iter()
+                .filter(|(reg, _)| *reg == REG_ECR)
+                .map(|&(_, value)| value)
+                .collect()
+        }
+    }
+
+    impl ErrorType for MockBus {
+        type Error = Infallible;
+    }
+
+    impl I2c for MockBus {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Infallible> {
+            assert_eq!(address, MPR121_ADDRESS);
+            for op in operations {
+                match op {
+                    Operation::Write(bytes) => {
+                        self.pointer = bytes[0] as usize;
+                        for &value in &bytes[1..] {
+                            self.writes.push((self.pointer as u8, value));
+                            self.regs[self.pointer] = value;
+                            if self.pointer == REG_SOFT_RESET as usize && value == SOFT_RESET {
+                                self.regs = [0; 0x81];
+                                if self.present {
+                                    self.regs[REG_CONFIG2 as usize] = 0x24;
+                                }
+                            }
+                            self.pointer += 1;
+                        }
+                    }
+                    Operation::Read(buf) => {
+                        for byte in buf.iter_mut() {
+                            *byte = self.regs[self.pointer];
+                            self.pointer += 1;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// IRQ line always asserted
+    struct MockIrq;
+
+    impl embedded_hal::digital::ErrorType for MockIrq {
+        type Error = Infallible;
+    }
+
+    impl Wait for MockIrq {
+        async fn wait_for_high(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+        async fn wait_for_low(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+        async fn wait_for_rising_edge(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+        async fn wait_for_falling_edge(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+        async fn wait_for_any_edge(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    fn thresholds(regs: &[u8]) -> Vec<(u8, u8)> {
+        (0..ELECTRODES)
+            .map(|i| {
+                let reg = REG_TOUCH_THRESHOLD as usize + 2 * i;
+                (regs[reg], regs[reg + 1])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn init_programs_the_registers() {
+        let mut dev = Mpr121::new(MockBus::new(), MPR121_ADDRESS);
+        let config = Mpr121Config::default();
+        assert_eq!(block_on(dev.init(config)), Ok(()));
+        let regs = &dev.i2c.regs;
+        assert_eq!(regs[0x2B..=0x35], [1, 1, 0x0E, 0, 1, 5, 1, 0, 0, 0, 0]);
+        assert_eq!(thresholds(regs), vec![(12, 6); ELECTRODES]);
+        assert_eq!(regs[REG_DEBOUNCE as usize], 0);
+        assert_eq!(regs[REG_CONFIG1 as usize], 0x10);
+        assert_eq!(regs[REG_CONFIG2 as usize], 0x20);
+        // stopped while configured, then running the 12 electrodes from fresh baselines
+        assert_eq!(dev.i2c.ecr_writes(), vec![0, ECR_BASELINE_INIT | 12]);
+    }
+
+    #[test]
+    fn init_fails_without_a_chip() {
+        let mut bus = MockBus::new();
+        bus.present = false;
+        let mut dev = Mpr121::new(bus, MPR121_ADDRESS);
+        assert_eq!(
+            block_on(dev.init(Mpr121Config::default())),
+            Err("MPR121 not found")
+        );
+        assert!(dev.i2c.ecr_writes().is_empty());
+    }
+
+    #[test]
+    fn set_thresholds_keeps_the_baselines() {
+        let mut dev = Mpr121::new(MockBus::new(), MPR121_ADDRESS);
+        block_on(dev.init(Mpr121Config::default())).unwrap();
+        dev.i2c.writes.clear();
+        block_on(dev.set_thresholds(20, 12)).unwrap();
+        assert_eq!(thresholds(&dev.i2c.regs), vec![(20, 12); ELECTRODES]);
+        assert_eq!(dev.i2c.ecr_writes(), vec![0, ECR_BASELINE_KEEP | 12]);
+        assert_eq!(dev.config().touch_threshold, 20);
+        assert_eq!(dev.config().release_threshold, 12);
+    }
+
+    #[test]
+    fn decodes_status_and_data() {
+        let mut dev = Mpr121::new(MockBus::new(), MPR121_ADDRESS);
+        dev.i2c.regs[0] = 0b0000_0101;
+        dev.i2c.regs[1] = 0x80 | 0b0000_1000;
+        assert_eq!(block_on(dev.touch_status()), Ok(0x8805));
+
+        let data = &mut dev.i2c.regs[REG_FILTERED_DATA as usize..];
+        data[..6].copy_from_slice(&[0xFF, 0x01, 0xFF, 0xFF, 0x34, 0x02]);
+        let filtered = block_on(dev.filtered_data()).unwrap();
+        // 10 bits, the unused high bits are masked
+        assert_eq!(filtered[..3], [0x1FF, 0x3FF, 0x234]);
+
+        dev.i2c.regs[REG_BASELINE as usize] = 0x40;
+        dev.i2c.regs[REG_BASELINE as usize + 11] = 0xFF;
+        let baselines = block_on(dev.baselines()).unwrap();
+        assert_eq!((baselines[0], baselines[11]), (0x100, 0x3FC));
+    }
+
+    #[test]
+    fn touch_source_reports_status_and_levels() {
+        let mut source = Mpr121TouchSource::new(MockBus::new(), MockIrq);
+        block_on(source.calibrate_start());
+        source.set_analog(true);
+        let regs = &mut source.dev.i2c.regs;
+        regs[0] = 1 << 2;
+        for i in 0..ELECTRODES {
+            regs[REG_BASELINE as usize + i] = 0x40;
+            regs[REG_FILTERED_DATA as usize + 2 * i + 1] = 0x01;
+        }
+        // 60 counts below the baseline of 256
+        regs[REG_FILTERED_DATA as usize + 4] = 0xC4;
+        regs[REG_FILTERED_DATA as usize + 5] = 0x00;
+
+        let status = block_on(source.run());
+        assert!(status[2] == TouchSensorStatus::On);
+        assert!(status[3] == TouchSensorStatus::Off);
+        assert!(status[ELECTRODES] == TouchSensorStatus::NA);
+        assert_eq!(source.levels()[2], 500);
+        assert_eq!(source.levels()[3], 0);
+    }
+
+    #[test]
+    fn over_current_is_not_available() {
+        let mut source = Mpr121TouchSource::new(MockBus::new(), MockIrq);
+        block_on(source.calibrate_start());
+        source.dev.i2c.regs[1] = 0x80;
+        assert!(block_on(source.run()) == [TouchSensorStatus::NA; NUM_SENSORS]);
+    }
+
+    #[test]
+    fn sensitivity_has_a_dead_band() {
+        let mut source = Mpr121TouchSource::new(MockBus::new(), MockIrq);
+        block_on(source.calibrate_start());
+        source.set_sensitivity(500);
+        block_on(source.run());
+        assert_eq!(thresholds(&source.dev.i2c.regs)[0], (22, 13));
+
+        source.dev.i2c.writes.clear();
+        // one step, knob noise
+        source.set_sensitivity(530);
+        block_on(source.run());
+        assert!(source.dev.i2c.ecr_writes().is_empty());
+        assert_eq!(source.threshold_level(0), 22 * 1000 / MPR121_FULL_LEVEL);
+
+        source.set_sensitivity(560);
+        block_on(source.run());
+        assert_eq!(thresholds(&source.dev.i2c.regs)[0], (20, 12));
+        assert_eq!(source.dev.i2c.ecr_writes(), vec![0, ECR_BASELINE_KEEP | 12]);
+
+        // the end of the range is reached even by a single step
+        source.set_sensitivity(980);
+        block_on(source.run());
+        assert_eq!(thresholds(&source.dev.i2c.regs)[0].0, 5);
+        source.set_sensitivity(1000);
+        block_on(source.run());
+        assert_eq!(
+            thresholds(&source.dev.i2c.regs)[0].0,
+            MPR121_TOUCH_THRESHOLD_MIN
+        );
+    }
+}
@@ -0,0 +1,109 @@
+// PIO-based discharge timing for the touch sensors
+
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::gpio::Pull;
+use embassy_rp::pio;
+use embassy_rp::{Peripheral, PeripheralRef};
+use embassy_time::Duration;
+use fixed::traits::ToFixed;
+use fixed::types::U56F8;
+use static_cell::StaticCell;
+
+use crate::board::{SensorPins, SensorsDma, SensorsPio};
+use crate::config::*;
+use crate::touch_sensors::DischargeTimer;
+
+pub struct PioDischargeTimer<'d> {
+    sm: pio::StateMachine<'d, SensorsPio, 0>,
+    dma: PeripheralRef<'d, SensorsDma>,
+    buf: &'d mut [u32; PIO_SENSOR_BUF_WORDS],
+    // must be kept alive while the state machine is in use
+    _common: pio::Common<'d, SensorsPio>,
+    _pins: [pio::Pin<'d, SensorsPio>; NUM_SENSORS],
+}
+
+static BUF: StaticCell<[u32; PIO_SENSOR_BUF_WORDS]> = StaticCell::new();
+
+fn sample_time(index: usize) -> Duration {
+    Duration::from_micros(index as u64 * 1_000_000 / PIO_SENSOR_SAMPLE_RATE as u64)
+}
+
+impl PioDischargeTimer<'static> {
+    pub fn new(sensor_pins: SensorPins) -> Self {
+        let SensorPins { pio, mut pins, dma } = sensor_pins;
+        let mut common = pio.common;
+        let mut sm = pio.sm0;
+
+        for pin in &mut pins {
+            pin.set_pull(Pull::None);
+        }
+        let pin_refs: [&pio::Pin<'static, SensorsPio>; NUM_SENSORS] =
+            core::array::from_fn(|i| &pins[i]);
+
+        let prg = pio_proc::pio_file!("src/touch_sensors.pio", select_program("touch_sensors"));
+        let mut cfg = pio::Config::default();
+        cfg.use_program(&common.load_program(&prg.program), &[]);
+        cfg.set_out_pins(&pin_refs);
+        cfg.set_in_pins(&pin_refs);
+        // two instructions per sample
+        cfg.clock_divider = (U56F8::from_num(clk_sys_freq())
+            / U56F8::from_num(2 * PIO_SENSOR_SAMPLE_RATE))
+        .to_fixed();
+        cfg.shift_out.direction = pio::ShiftDirection::Right;
+        cfg.shift_in.auto_fill = true;
+        cfg.shift_in.threshold = 32;
+        cfg.shift_in.direction = pio::ShiftDirection::Right;
+        sm.set_pin_dirs(pio::Direction::In, &pin_refs);
+        sm.set_config(&cfg);
+        sm.set_enable(true);
+
+        Self {
+            sm,
+            dma: dma.into_ref(),
+            buf: BUF.init([0; PIO_SENSOR_BUF_WORDS]),
+            _common: common,
+            _pins: pins,
+        }
+    }
+}
+
+impl<'d> DischargeTimer for PioDischargeTimer<'d> {
+    async fn measure_until(
+        &mut self,
+        limit: Duration,
+        pins: u32,
+    ) -> [Option<Duration>; NUM_SENSORS] {
+        // the buffer holds PIO_SENSOR_MAX_TIME worth of samples, pins still
+        // high after that read None
+        let limit = limit.min(PIO_SENSOR_MAX_TIME);
+        let samples = (limit.as_micros() * PIO_SENSOR_SAMPLE_RATE as u64 / 1_000_000) as usize + 1;
+        let words = samples.div_ceil(2).clamp(1, PIO_SENSOR_BUF_WORDS);
+
+        let buf = &mut self.buf[..words];
+        let (rx, tx) = self.sm.rx_tx();
+        // start the DMA first, so the samples never stall on a full FIFO
+        let transfer = rx.dma_pull(self.dma.reborrow(), buf);
+        tx.push((words * 2 - 1) as u32);
+        tx.push(pins);
+        transfer.await;
+
+        let mut times = [None; NUM_SENSORS];
+        // the guards read low all the time
+        let mut pending: u32 = pins & ((1 << NUM_SENSORS) - 1);
+        for (word_index, word) in self.buf[..words].iter().enumerate() {
+            for (half, sample) in [*word & 0xffff, *word >> 16].into_iter().enumerate() {
+                let mut went_low = pending & !sample;
+                while went_low != 0 {
+                    let i = went_low.trailing_zeros() as usize;
+                    times[i] = Some(sample_time(word_index * 2 + half));
+                    went_low &= went_low - 1;
+                }
+                pending &= sample;
+            }
+            if pending == 0 {
+                break;
+            }
+        }
+        times
+    }
+}
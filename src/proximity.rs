@@ -0,0 +1,268 @@
+// Proximity control: sensors used as continuous controllers, driven by
+// the analog level of a hand approaching them
+
+use embassy_time::Instant;
+
+use crate::config::*;
+use crate::midi::{MidiMsg, U14, U7};
+
+#[derive(Clone, Copy)]
+struct ProximityState {
+    // filtered level, scaled by PROXIMITY_FILTER_DIV
+    filtered: u32,
+    // last value sent, in 0..=1000
+    sent: Option<u32>,
+    sent_at: Instant,
+    // note playing in the `Note` mode
+    note: Option<U7>,
+}
+
+pub struct ProximityController {
+    modes: [Option<ProximityMode>; NUM_SENSORS],
+    states: [ProximityState; NUM_SENSORS],
+}
+
+impl ProximityController {
+    pub fn new(modes: [Option<ProximityMode>; NUM_SENSORS]) -> Self {
+        Self {
+            modes,
+            states: [ProximityState {
+                filtered: 0,
+                sent: None,
+                sent_at: Instant::MIN,
+                note: None,
+            }; NUM_SENSORS],
+        }
+    }
+    /// Sensor `i` is a proximity controller, not a key
+    pub fn is_controller(&self, i: usize) -> bool {
+        self.modes[i].is_some()
+    }
+    pub fn is_active(&self) -> bool {
+        self.modes.iter().any(|m| m.is_some())
+    }
+    /// Messages to send for the new `level` of sensor `i`
+    pub fn update(&mut self, i: usize, level: u32, now: Instant) -> [Option<MidiMsg>; 2] {
+        let Some(mode) = self.modes[i] else {
+            return [None, None];
+        };
+        let state = &mut self.states[i];
+        state.filtered = state.filtered - state.filtered / PROXIMITY_FILTER_DIV + level.min(1000);
+        let level = state.filtered / PROXIMITY_FILTER_DIV;
+        // position in the range, None when too far
+        let position = if level < PROXIMITY_LEVEL_MIN {
+            None
+        } else {
+            let range = PROXIMITY_LEVEL_MAX - PROXIMITY_LEVEL_MIN;
+            Some(((level - PROXIMITY_LEVEL_MIN) * 1000 / range).min(1000))
+        };
+        let value = position.unwrap_or(0);
+
+        if now < state.sent_at + PROXIMITY_INTERVAL {
+            return [None, None];
+        }
+        match mode {
+            ProximityMode::PitchBend => {
+                // only full 7-bit steps, the level is not more precise than that
+                let value = value * 127 / 1000;
+                if state.sent == Some(value) {
+                    return [None, None];
+                }
+                state.sent = Some(value);
+                state.sent_at = now;
+                [
+                    Some(MidiMsg::PitchBend {
+                        channel: MIDI_OUT_CHANNEL,
+                        value: U14::from_signed((value * 8191 / 127) as i32),
+                    }),
+                    None,
+                ]
+            }
+            ProximityMode::Control(control) => {
+                let value = value * 127 / 1000;
+                if state.sent == Some(value) {
+                    return [None, None];
+                }
+                state.sent = Some(value);
+                state.sent_at = now;
+                [
+                    Some(MidiMsg::ControlChange {
+                        channel: MIDI_OUT_CHANNEL,
+                        control,
+                        value: U7::saturating(value as i32),
+                    }),
+                    None,
+                ]
+            }
+            ProximityMode::Note { low, high } => {
+                let note = position.and_then(|p| {
+                    let span = high.get().saturating_sub(low.get()) as u32;
+                    low.checked_add((span * p / 1000) as i32)
+                });
+                if note == state.note {
+                    return [None, None];
+                }
+                let off = state.note.map(|note| MidiMsg::NoteOff {
+                    channel: MIDI_OUT_CHANNEL,
+                    note,
+                    velocity: U7::MIN,
+                });
+                let on = note.map(|note| MidiMsg::NoteOn {
+                    channel: MIDI_OUT_CHANNEL,
+                    note,
+                    velocity: PROXIMITY_NOTE_VELOCITY,
+                });
+                state.note = note;
+                state.sent_at = now;
+                [off, on]
+            }
+        }
+    }
+    /// Message stopping the controller of sensor `i`: a note-off or the
+    /// controller back at its rest value
+    pub fn release(&mut self, i: usize) -> Option<MidiMsg> {
+        let state = &mut self.states[i];
+        let sent = state.sent.take();
+        state.filtered = 0;
+        match self.modes[i]? {
+            ProximityMode::PitchBend => sent.map(|_| MidiMsg::PitchBend {
+                channel: MIDI_OUT_CHANNEL,
+                value: U14::CENTER,
+            }),
+            ProximityMode::Control(control) => sent.map(|_| MidiMsg::ControlChange {
+                channel: MIDI_OUT_CHANNEL,
+                control,
+                value: U7::MIN,
+            }),
+            ProximityMode::Note { .. } => state.note.take().map(|note| MidiMsg::NoteOff {
+                channel: MIDI_OUT_CHANNEL,
+                note,
+                velocity: U7::MIN,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller(mode: ProximityMode) -> ProximityController {
+        let mut modes = [None; NUM_SENSORS];
+        modes[0] = Some(mode);
+        ProximityController::new(modes)
+    }
+
+    /// Hold sensor 0 at `level` until the filter settles, returns the messages sent
+    fn hold(controller: &mut ProximityController, level: u32, now: &mut Instant) -> Vec<MidiMsg> {
+        let mut msgs = Vec::new();
+        for _ in 0..100 {
+            *now += PROXIMITY_INTERVAL;
+            msgs.extend(controller.update(0, level, *now).into_iter().flatten());
+        }
+        msgs
+    }
+
+    #[test]
+    fn keys_are_not_controllers() {
+        let mut controller = controller(ProximityMode::PitchBend);
+        assert!(controller.is_active());
+        assert!(controller.is_controller(0));
+        assert!(!controller.is_controller(1));
+        let now = Instant::from_secs(1);
+        assert!(controller.update(1, 1000, now) == [None, None]);
+        assert!(!ProximityController::new(PROXIMITY_SENSORS).is_active());
+    }
+
+    #[test]
+    fn control_follows_the_hand() {
+        let control = U7::saturating(7);
+        let mut controller = controller(ProximityMode::Control(control));
+        let mut now = Instant::from_secs(1);
+        let msgs = hold(&mut controller, 1000, &mut now);
+        assert!(
+            msgs.last()
+                == Some(&MidiMsg::ControlChange {
+                    channel: MIDI_OUT_CHANNEL,
+                    control,
+                    value: U7::MAX,
+                })
+        );
+        // values rise step by step, never sent twice
+        assert!(msgs.windows(2).all(|pair| pair[0] != pair[1]));
+        assert!(
+            controller.release(0)
+                == Some(MidiMsg::ControlChange {
+                    channel: MIDI_OUT_CHANNEL,
+                    control,
+                    value: U7::MIN,
+                })
+        );
+        assert!(controller.release(0).is_none());
+    }
+
+    #[test]
+    fn rate_limited() {
+        let mut controller = controller(ProximityMode::Control(U7::MIN));
+        let now = Instant::from_secs(1);
+        assert!(controller.update(0, 1000, now)[0].is_some());
+        assert!(controller.update(0, 1000, now) == [None, None]);
+        let later = now + PROXIMITY_INTERVAL;
+        assert!(controller.update(0, 1000, later)[0].is_some());
+    }
+
+    #[test]
+    fn pitch_bend_up_and_back() {
+        let mut controller = controller(ProximityMode::PitchBend);
+        let mut now = Instant::from_secs(1);
+        let msgs = hold(&mut controller, 1000, &mut now);
+        assert!(
+            msgs.last()
+                == Some(&MidiMsg::PitchBend {
+                    channel: MIDI_OUT_CHANNEL,
+                    value: U14::MAX,
+                })
+        );
+        assert!(
+            controller.release(0)
+                == Some(MidiMsg::PitchBend {
+                    channel: MIDI_OUT_CHANNEL,
+                    value: U14::CENTER,
+                })
+        );
+    }
+
+    #[test]
+    fn notes_while_near() {
+        let low = U7::saturating(60);
+        let high = U7::saturating(72);
+        let mut controller = controller(ProximityMode::Note { low, high });
+        let mut now = Instant::from_secs(1);
+        let note_on = |note| MidiMsg::NoteOn {
+            channel: MIDI_OUT_CHANNEL,
+            note,
+            velocity: PROXIMITY_NOTE_VELOCITY,
+        };
+        let note_off = |note| MidiMsg::NoteOff {
+            channel: MIDI_OUT_CHANNEL,
+            note,
+            velocity: U7::MIN,
+        };
+        // too far for a note
+        assert!(hold(&mut controller, PROXIMITY_LEVEL_MIN - 1, &mut now).is_empty());
+        let msgs = hold(&mut controller, 1000, &mut now);
+        assert!(matches!(msgs.first(), Some(MidiMsg::NoteOn { .. })));
+        assert!(msgs.last() == Some(&note_on(high)));
+        // up the range, every note stopped before the next one
+        for pair in msgs[1..].chunks(2) {
+            let (MidiMsg::NoteOff { note: off, .. }, MidiMsg::NoteOn { note: on, .. }) =
+                (pair[0], pair[1])
+            else {
+                panic!("note-off and note-on expected");
+            };
+            assert!(off < on && on <= high);
+        }
+        assert!(controller.release(0) == Some(note_off(high)));
+        assert!(controller.release(0).is_none());
+    }
+}
@@ -0,0 +1,126 @@
+// Runtime detection of misbehaving sensors
+
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+
+use crate::config::*;
+
+#[derive(Clone, Copy, Format, PartialEq)]
+pub enum SensorFault {
+    // discharges too fast, shorted to ground
+    Shorted,
+    // touched without a release for longer than anyone holds a key
+    StuckOn,
+    // toggling faster than anyone can play
+    Erratic,
+}
+
+#[derive(Clone, Copy)]
+pub struct SensorHealth {
+    // start of the current touch, None while released
+    touched_since: Option<Instant>,
+    toggles: u32,
+    toggles_since: Instant,
+    erratic: bool,
+    shorted: bool,
+    fault: Option<SensorFault>,
+    healthy_since: Instant,
+}
+
+impl SensorHealth {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            touched_since: None,
+            toggles: 0,
+            toggles_since: now,
+            erratic: false,
+            shorted: false,
+            fault: None,
+            healthy_since: now,
+        }
+    }
+    pub fn fault(&self) -> Option<SensorFault> {
+        self.fault
+    }
+    /// Feed a discharge time measured in analog mode
+    pub fn analog_sample(&mut self, time: Option<Duration>) {
+        self.shorted = matches!(time, Some(t) if t < MIN_TIME_REQUIRED);
+    }
+    /// Feed the filtered touch state after a scan, returns the current fault
+    pub fn update(&mut self, touched: bool, now: Instant) -> Option<SensorFault> {
+        if touched != self.touched_since.is_some() {
+            self.touched_since = touched.then_some(now);
+            self.toggles += 1;
+        }
+        if now - self.toggles_since >= FAULT_TOGGLE_WINDOW {
+            self.erratic = self.toggles > FAULT_MAX_TOGGLES;
+            self.toggles = 0;
+            self.toggles_since = now;
+        }
+
+        let problem = if self.shorted {
+            Some(SensorFault::Shorted)
+        } else if matches!(self.touched_since, Some(since) if now - since >= FAULT_STUCK_ON_TIME) {
+            Some(SensorFault::StuckOn)
+        } else if self.erratic {
+            Some(SensorFault::Erratic)
+        } else {
+            None
+        };
+
+        match (self.fault, problem) {
+            (_, Some(problem)) => {
+                self.fault = Some(problem);
+                self.healthy_since = now;
+            }
+            (Some(_), None) => {
+                if now - self.healthy_since >= FAULT_RECOVERY_TIME {
+                    self.fault = None;
+                }
+            }
+            (None, None) => (),
+        }
+        self.fault
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stuck_on_after_a_release() {
+        let start = Instant::from_secs(1);
+        let mut health = SensorHealth::new(start);
+        assert!(health.update(false, start).is_none());
+        assert!(health
+            .update(true, start + Duration::from_secs(1))
+            .is_none());
+        let almost =
+            start + Duration::from_secs(1) + FAULT_STUCK_ON_TIME - Duration::from_millis(1);
+        assert!(health.update(true, almost).is_none());
+        let held = start + Duration::from_secs(1) + FAULT_STUCK_ON_TIME;
+        assert!(health.update(true, held) == Some(SensorFault::StuckOn));
+    }
+
+    #[test]
+    fn stuck_on_from_the_start() {
+        let start = Instant::from_secs(1);
+        let mut health = SensorHealth::new(start);
+        assert!(health.update(true, start).is_none());
+        let held = start + FAULT_STUCK_ON_TIME;
+        assert!(health.update(true, held) == Some(SensorFault::StuckOn));
+    }
+
+    #[test]
+    fn recovers_after_release() {
+        let start = Instant::from_secs(1);
+        let mut health = SensorHealth::new(start);
+        health.update(true, start);
+        let held = start + FAULT_STUCK_ON_TIME;
+        assert!(health.update(true, held) == Some(SensorFault::StuckOn));
+        assert!(health.update(false, held) == Some(SensorFault::StuckOn));
+        let later = held + FAULT_RECOVERY_TIME;
+        assert!(health.update(false, later).is_none());
+    }
+}
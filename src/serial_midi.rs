@@ -1,14 +1,19 @@
 use defmt::info;
 use embassy_rp::uart::BufferedUartTx;
+use embassy_time::Instant;
+use embedded_io_async::Write;
 use static_cell::StaticCell;
 
 use crate::board::{Irqs, MidiTxPin, MidiUart};
 use crate::config::SERIAL_MIDI_BUF_LEN;
-use crate::midi::MidiChannelReceiver;
+use crate::din_output::{DinQueue, RunningStatusEncoder};
+use crate::midi::{MidiChannelReceiver, MidiMsg};
 
 pub struct SerialMidi<'d> {
     uart: embassy_rp::uart::BufferedUartTx<'d, MidiUart>,
     midi_rx: MidiChannelReceiver<'d>,
+    queue: DinQueue,
+    encoder: RunningStatusEncoder,
 }
 
 impl<'d> SerialMidi<'d> {
@@ -26,14 +31,34 @@ impl<'d> SerialMidi<'d> {
 
         let uart = BufferedUartTx::new(uart, Irqs, tx_pin, buf, config);
 
-        Self { uart, midi_rx }
+        Self {
+            uart,
+            midi_rx,
+            queue: DinQueue::new(),
+            encoder: RunningStatusEncoder::new(),
+        }
     }
     pub async fn task(&mut self) -> ! {
+        let mut buf = [0u8; MidiMsg::MAX_LEN];
         loop {
-            let msg = self.midi_rx.receive().await;
+            if self.queue.is_empty() {
+                self.queue.push(self.midi_rx.receive().await);
+            }
+            // whatever came while the previous message was being written
+            while !self.queue.is_full() {
+                let Ok(msg) = self.midi_rx.try_receive() else {
+                    break;
+                };
+                self.queue.push(msg);
+            }
+            let Some(msg) = self.queue.pop() else {
+                continue;
+            };
             info!("serial: msg: {}", msg);
-            if let Err(err) = msg.send_bytes(&mut self.uart).await {
-                info!("Midi send error: {}", err);
+            let num_bytes = self.encoder.encode(&msg, Instant::now(), &mut buf);
+            if self.uart.write_all(&buf[..num_bytes]).await.is_err() {
+                info!("Midi send error");
+                self.encoder.reset();
             }
         }
     }
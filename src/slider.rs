@@ -0,0 +1,308 @@
+// Sliders: rows of adjacent sensors read as one continuous control, the
+// finger position is interpolated from the levels of the neighbouring pads
+
+use embassy_time::Instant;
+
+use crate::config::*;
+use crate::midi::{MidiMsg, U14, U7};
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum SliderOutput {
+    // -8192 at the first pad, 0 in the middle, 8191 at the last one, back to the
+    // center when released
+    PitchBend,
+    // 14-bit controller (MSB number, the LSB one is 32 above), kept when released
+    Control(U7),
+}
+
+/// Consecutive sensors forming a slider (see `SLIDERS`)
+#[derive(Clone, Copy)]
+pub struct Slider {
+    pub first: usize,
+    pub count: usize,
+    pub output: SliderOutput,
+    // notes of the nearest pad played too, from this note at the first pad
+    pub glissando: Option<U7>,
+}
+
+#[derive(Clone, Copy)]
+struct SliderState {
+    // filtered position in 1/1000 pads from the first one, scaled by SLIDER_FILTER_DIV,
+    // None when not touched
+    filtered: Option<u32>,
+    // last 14-bit value sent
+    sent: Option<u16>,
+    sent_at: Instant,
+    // glissando note playing
+    note: Option<U7>,
+}
+
+pub struct SliderController {
+    sliders: [Option<Slider>; MAX_SLIDERS],
+    states: [SliderState; MAX_SLIDERS],
+}
+
+impl SliderController {
+    pub fn new(sliders: [Option<Slider>; MAX_SLIDERS]) -> Self {
+        Self {
+            sliders,
+            states: [SliderState {
+                filtered: None,
+                sent: None,
+                sent_at: Instant::MIN,
+                note: None,
+            }; MAX_SLIDERS],
+        }
+    }
+    /// Sensor `i` is a pad of a slider, not a key
+    pub fn is_pad(&self, i: usize) -> bool {
+        self.sliders
+            .iter()
+            .flatten()
+            .any(|s| (s.first..s.first + s.count).contains(&i))
+    }
+    pub fn is_active(&self) -> bool {
+        self.sliders.iter().any(|s| s.is_some())
+    }
+    /// Finger position on the pads in 1/1000 pads from the first one, from
+    /// the levels around the strongest pad
+    fn position(levels: &[u32]) -> Option<u32> {
+        let (peak, &peak_level) = levels.iter().enumerate().max_by_key(|(_, &l)| l)?;
+        if peak_level < SLIDER_MIN_LEVEL {
+            return None;
+        }
+        let mut sum = 0;
+        let mut weights = 0;
+        let first = peak.saturating_sub(1);
+        let last = (peak + 1).min(levels.len() - 1);
+        for (k, level) in levels.iter().enumerate().take(last + 1).skip(first) {
+            let weight = level.saturating_sub(SLIDER_LEVEL_FLOOR);
+            sum += k as u32 * 1000 * weight;
+            weights += weight;
+        }
+        Some(sum.checked_div(weights).unwrap_or(peak as u32 * 1000))
+    }
+    /// Messages to send for the new levels of all the sensors
+    pub fn update(
+        &mut self,
+        levels: &[u32; NUM_SENSORS],
+        now: Instant,
+    ) -> [Option<MidiMsg>; 4 * MAX_SLIDERS] {
+        let mut msgs = [None; 4 * MAX_SLIDERS];
+        let mut n = 0;
+        for (slider, state) in self.sliders.iter().zip(self.states.iter_mut()) {
+            let Some(slider) = slider else {
+                continue;
+            };
+            let Some(pads) = levels.get(slider.first..slider.first + slider.count) else {
+                continue;
+            };
+            if now < state.sent_at + SLIDER_INTERVAL {
+                continue;
+            }
+            let position = Self::position(pads).map(|p| {
+                // follow a new touch right away, filter the moves
+                let filtered = match state.filtered {
+                    Some(f) => f - f / SLIDER_FILTER_DIV + p,
+                    None => p * SLIDER_FILTER_DIV,
+                };
+                state.filtered = Some(filtered);
+                filtered / SLIDER_FILTER_DIV
+            });
+            if position.is_none() {
+                state.filtered = None;
+            }
+            let span = (slider.count.max(2) as u32 - 1) * 1000;
+            // scaled to 16384 so the middle is the exact center, the top saturates
+            let value = position.map(|p| (p.min(span) * 16384 / span).min(16383) as u16);
+
+            match (slider.output, value) {
+                (_, Some(value)) if state.sent == Some(value) => (),
+                (SliderOutput::PitchBend, Some(value)) => {
+                    msgs[n] = Some(MidiMsg::PitchBend {
+                        channel: MIDI_OUT_CHANNEL,
+                        value: U14::saturating(value as i32),
+                    });
+                    n += 1;
+                    state.sent = Some(value);
+                }
+                // spring back to the center when released
+                (SliderOutput::PitchBend, None) => {
+                    if state.sent.take().is_some() {
+                        msgs[n] = Some(MidiMsg::PitchBend {
+                            channel: MIDI_OUT_CHANNEL,
+                            value: U14::CENTER,
+                        });
+                        n += 1;
+                    }
+                }
+                // 14-bit controller, the LSB controller is 32 above the MSB one
+                (SliderOutput::Control(control), Some(value)) => {
+                    let value14 = U14::saturating(value as i32);
+                    msgs[n] = Some(MidiMsg::ControlChange {
+                        channel: MIDI_OUT_CHANNEL,
+                        control,
+                        value: value14.msb(),
+                    });
+                    n += 1;
+                    if let Some(control_lsb) = control.checked_add(32) {
+                        msgs[n] = Some(MidiMsg::ControlChange {
+                            channel: MIDI_OUT_CHANNEL,
+                            control: control_lsb,
+                            value: value14.lsb(),
+                        });
+                        n += 1;
+                    }
+                    state.sent = Some(value);
+                }
+                // stays where it was left, like a fader
+                (SliderOutput::Control(_), None) => (),
+            }
+
+            if let Some(base) = slider.glissando {
+                // the note of the nearest pad
+                let note = position.and_then(|p| base.checked_add(((p + 500) / 1000) as i32));
+                if note != state.note {
+                    if let Some(note) = state.note {
+                        msgs[n] = Some(MidiMsg::NoteOff {
+                            channel: MIDI_OUT_CHANNEL,
+                            note,
+                            velocity: U7::MIN,
+                        });
+                        n += 1;
+                    }
+                    if let Some(note) = note {
+                        msgs[n] = Some(MidiMsg::NoteOn {
+                            channel: MIDI_OUT_CHANNEL,
+                            note,
+                            velocity: SLIDER_NOTE_VELOCITY,
+                        });
+                        n += 1;
+                    }
+                    state.note = note;
+                }
+            }
+            state.sent_at = now;
+        }
+        msgs
+    }
+    /// Note-offs for the glissando notes playing, pitch bends back to the center
+    pub fn release_all(&mut self) -> [Option<MidiMsg>; 2 * MAX_SLIDERS] {
+        let mut msgs = [None; 2 * MAX_SLIDERS];
+        let mut n = 0;
+        for (slider, state) in self.sliders.iter().zip(self.states.iter_mut()) {
+            state.filtered = None;
+            if let Some(note) = state.note.take() {
+                msgs[n] = Some(MidiMsg::NoteOff {
+                    channel: MIDI_OUT_CHANNEL,
+                    note,
+                    velocity: U7::MIN,
+                });
+                n += 1;
+            }
+            if let Some(Slider {
+                output: SliderOutput::PitchBend,
+                ..
+            }) = slider
+            {
+                if state.sent.take().is_some() {
+                    msgs[n] = Some(MidiMsg::PitchBend {
+                        channel: MIDI_OUT_CHANNEL,
+                        value: U14::CENTER,
+                    });
+                    n += 1;
+                }
+            }
+        }
+        msgs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GLISSANDO_BEND: Slider = Slider {
+        first: 0,
+        count: 3,
+        output: SliderOutput::PitchBend,
+        glissando: Some(U7::saturating(60)),
+    };
+
+    fn bend(value: u16) -> Option<MidiMsg> {
+        Some(MidiMsg::PitchBend {
+            channel: MIDI_OUT_CHANNEL,
+            value: U14::new(value).unwrap(),
+        })
+    }
+
+    fn note_on(note: u8) -> Option<MidiMsg> {
+        Some(MidiMsg::NoteOn {
+            channel: MIDI_OUT_CHANNEL,
+            note: U7::new(note).unwrap(),
+            velocity: SLIDER_NOTE_VELOCITY,
+        })
+    }
+
+    fn note_off(note: u8) -> Option<MidiMsg> {
+        Some(MidiMsg::NoteOff {
+            channel: MIDI_OUT_CHANNEL,
+            note: U7::new(note).unwrap(),
+            velocity: U7::MIN,
+        })
+    }
+
+    #[test]
+    fn touch_and_release() {
+        let mut sliders = SliderController::new([Some(GLISSANDO_BEND), None]);
+        let mut levels = [0; NUM_SENSORS];
+        let mut now = Instant::from_secs(1);
+        levels[1] = 1000;
+        let msgs = sliders.update(&levels, now);
+        // the middle pad of 3
+        assert!(msgs[..3] == [bend(U14::CENTER.get()), note_on(61), None]);
+
+        levels[1] = 0;
+        now += SLIDER_INTERVAL;
+        let msgs = sliders.update(&levels, now);
+        assert!(msgs[..3] == [bend(U14::CENTER.get()), note_off(61), None]);
+    }
+
+    #[test]
+    fn release_all_stops_the_note_and_centers_the_bend() {
+        let mut sliders = SliderController::new([Some(GLISSANDO_BEND), None]);
+        let mut levels = [0; NUM_SENSORS];
+        levels[2] = 1000;
+        sliders.update(&levels, Instant::from_secs(1));
+        assert!(sliders.release_all() == [note_off(62), bend(U14::CENTER.get()), None, None]);
+        assert!(sliders.release_all() == [None; 2 * MAX_SLIDERS]);
+        // nothing left to spring back
+        levels[2] = 0;
+        let msgs = sliders.update(&levels, Instant::from_secs(2));
+        assert!(msgs == [None; 4 * MAX_SLIDERS]);
+    }
+
+    #[test]
+    fn control_is_kept_when_released() {
+        let fader = Slider {
+            first: 4,
+            count: 2,
+            output: SliderOutput::Control(U7::saturating(1)),
+            glissando: None,
+        };
+        let mut sliders = SliderController::new([None, Some(fader)]);
+        let mut levels = [0; NUM_SENSORS];
+        levels[5] = 1000;
+        let msgs = sliders.update(&levels, Instant::from_secs(1));
+        let cc = |control, value| {
+            Some(MidiMsg::ControlChange {
+                channel: MIDI_OUT_CHANNEL,
+                control: U7::new(control).unwrap(),
+                value: U7::new(value).unwrap(),
+            })
+        };
+        assert!(msgs[..3] == [cc(1, 127), cc(33, 127), None]);
+        assert!(sliders.release_all() == [None; 2 * MAX_SLIDERS]);
+    }
+}
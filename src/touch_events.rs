@@ -0,0 +1,209 @@
+// Touch events: the changes of the sensor states, published to any number
+// of subscribers (MIDI, LEDs, recording...)
+
+use defmt::{info, Format};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::{PubSubChannel, Publisher, Subscriber};
+use embassy_time::Instant;
+
+use crate::config::*;
+use crate::touch_sensors::TouchSensorStatus;
+
+#[derive(Clone, Copy, Format, PartialEq)]
+pub enum TouchEventKind {
+    Touched,
+    Released,
+    // disabled as faulty, a touched sensor is released by this too
+    Broken,
+}
+
+#[derive(Clone, Copy, Format)]
+pub struct TouchEvent {
+    pub kind: TouchEventKind,
+    pub sensor: usize,
+    pub time: Instant,
+    // level (permile, see `TouchSensors::level()`) in the scan the change
+    // was detected in, 0 when not measuring levels
+    pub strength: u32,
+}
+
+pub type TouchEventChannel = PubSubChannel<
+    CriticalSectionRawMutex,
+    TouchEvent,
+    TOUCH_EVENT_QUEUE_SIZE,
+    TOUCH_EVENT_SUBSCRIBERS,
+    TOUCH_EVENT_PUBLISHERS,
+>;
+pub type TouchEventPublisher<'ch> = Publisher<
+    'ch,
+    CriticalSectionRawMutex,
+    TouchEvent,
+    TOUCH_EVENT_QUEUE_SIZE,
+    TOUCH_EVENT_SUBSCRIBERS,
+    TOUCH_EVENT_PUBLISHERS,
+>;
+pub type TouchEventSubscriber<'ch> = Subscriber<
+    'ch,
+    CriticalSectionRawMutex,
+    TouchEvent,
+    TOUCH_EVENT_QUEUE_SIZE,
+    TOUCH_EVENT_SUBSCRIBERS,
+    TOUCH_EVENT_PUBLISHERS,
+>;
+
+/// Events of a single scan, at most one per sensor
+#[derive(Clone, Copy)]
+pub struct TouchEvents {
+    events: [Option<TouchEvent>; NUM_SENSORS],
+    len: usize,
+}
+
+impl TouchEvents {
+    fn new() -> Self {
+        Self {
+            events: [None; NUM_SENSORS],
+            len: 0,
+        }
+    }
+    fn push(&mut self, event: TouchEvent) {
+        self.events[self.len] = Some(event);
+        self.len += 1;
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &TouchEvent> {
+        self.events[..self.len].iter().flatten()
+    }
+    /// The events waiting for `subscriber`, at most a scan's worth, the ones
+    /// lost by lagging behind are skipped
+    pub fn receive(subscriber: &mut TouchEventSubscriber) -> Self {
+        let mut events = Self::new();
+        while events.len < NUM_SENSORS {
+            let Some(event) = subscriber.try_next_message_pure() else {
+                break;
+            };
+            events.push(event);
+        }
+        events
+    }
+    /// Publish the events, subscribers not keeping up lose the oldest ones
+    pub fn publish(&self, publisher: &TouchEventPublisher) {
+        for event in self.iter() {
+            publisher.publish_immediate(*event);
+        }
+    }
+}
+
+/// Edge detection on the sensor states returned by `TouchSource::run()`
+pub struct TouchEventDetector {
+    status: [TouchSensorStatus; NUM_SENSORS],
+}
+
+impl Default for TouchEventDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TouchEventDetector {
+    pub fn new() -> Self {
+        Self {
+            status: [TouchSensorStatus::NA; NUM_SENSORS],
+        }
+    }
+    /// Events for the changes since the previous scan, sensors becoming
+    /// unavailable (NA) keep their previous state
+    pub fn update(
+        &mut self,
+        status: &[TouchSensorStatus; NUM_SENSORS],
+        levels: &[u32; NUM_SENSORS],
+        now: Instant,
+    ) -> TouchEvents {
+        let mut events = TouchEvents::new();
+        for (i, (prev, cur)) in self.status.iter_mut().zip(status.iter()).enumerate() {
+            if *cur == *prev {
+                continue;
+            }
+            let kind = match *cur {
+                TouchSensorStatus::NA => continue,
+                TouchSensorStatus::On => TouchEventKind::Touched,
+                TouchSensorStatus::Off => TouchEventKind::Released,
+                TouchSensorStatus::Broken => TouchEventKind::Broken,
+            };
+            *prev = *cur;
+            info!("{}: {}", kind, i);
+            events.push(TouchEvent {
+                kind,
+                sensor: i,
+                time: now,
+                strength: levels[i],
+            });
+        }
+        events
+    }
+    /// Release all the touched sensors, when the scanning stops
+    pub fn release_all(&mut self, now: Instant) -> TouchEvents {
+        let mut events = TouchEvents::new();
+        for (i, status) in self.status.iter_mut().enumerate() {
+            if *status == TouchSensorStatus::On {
+                events.push(TouchEvent {
+                    kind: TouchEventKind::Released,
+                    sensor: i,
+                    time: now,
+                    strength: 0,
+                });
+            }
+            *status = TouchSensorStatus::NA;
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(events: &TouchEvents) -> Vec<(TouchEventKind, usize)> {
+        events.iter().map(|e| (e.kind, e.sensor)).collect()
+    }
+
+    #[test]
+    fn subscriber_receives_the_published_scan() {
+        let channel = TouchEventChannel::new();
+        let publisher = channel.publisher().unwrap();
+        let mut subscriber = channel.subscriber().unwrap();
+        let mut detector = TouchEventDetector::new();
+        let mut status = [TouchSensorStatus::NA; NUM_SENSORS];
+        let levels = [0; NUM_SENSORS];
+        let now = Instant::from_ticks(0);
+
+        // not available yet, no events
+        detector.update(&status, &levels, now).publish(&publisher);
+        assert_eq!(TouchEvents::receive(&mut subscriber).len, 0);
+
+        status[1] = TouchSensorStatus::Off;
+        status[3] = TouchSensorStatus::On;
+        detector.update(&status, &levels, now).publish(&publisher);
+        let events = TouchEvents::receive(&mut subscriber);
+        assert!(kinds(&events) == [(TouchEventKind::Released, 1), (TouchEventKind::Touched, 3)]);
+
+        // a whole scan of changes fits in the queue
+        status = [TouchSensorStatus::Broken; NUM_SENSORS];
+        detector.update(&status, &levels, now).publish(&publisher);
+        assert_eq!(TouchEvents::receive(&mut subscriber).len, NUM_SENSORS);
+        assert_eq!(TouchEvents::receive(&mut subscriber).len, 0);
+    }
+
+    #[test]
+    fn release_all_releases_the_touched_sensors() {
+        let mut detector = TouchEventDetector::new();
+        let mut status = [TouchSensorStatus::Off; NUM_SENSORS];
+        status[2] = TouchSensorStatus::On;
+        status[5] = TouchSensorStatus::On;
+        let now = Instant::from_ticks(0);
+        detector.update(&status, &[0; NUM_SENSORS], now);
+        let events = detector.release_all(now);
+        assert!(kinds(&events) == [(TouchEventKind::Released, 2), (TouchEventKind::Released, 5)]);
+        // scanning again starts from scratch
+        let events = detector.update(&status, &[0; NUM_SENSORS], now);
+        assert_eq!(events.len, NUM_SENSORS);
+    }
+}
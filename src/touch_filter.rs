@@ -0,0 +1,113 @@
+// Debouncing of the raw touch sensor samples
+
+use crate::config::TouchFilterKind;
+
+#[derive(Clone, Copy)]
+pub struct TouchFilter {
+    kind: TouchFilterKind,
+    // recent samples, the newest in the lowest bit (Majority)
+    history: u32,
+    // Integrator
+    count: u8,
+    touched: bool,
+}
+
+impl TouchFilter {
+    pub const fn new(kind: TouchFilterKind) -> Self {
+        Self {
+            kind,
+            history: 0,
+            count: 0,
+            touched: false,
+        }
+    }
+    pub fn reset(&mut self) {
+        *self = Self::new(self.kind);
+    }
+    pub fn is_touched(&self) -> bool {
+        self.touched
+    }
+    /// Feed a single raw sample, returns the filtered state
+    pub fn update(&mut self, on: bool) -> bool {
+        match self.kind {
+            TouchFilterKind::Majority { n, m } => {
+                let m = m.clamp(1, 32) as u32;
+                let mask = if m == 32 { u32::MAX } else { (1 << m) - 1 };
+                self.history = ((self.history << 1) | on as u32) & mask;
+                let ons = self.history.count_ones();
+                if ons >= n as u32 {
+                    self.touched = true;
+                } else if m - ons >= n as u32 {
+                    self.touched = false;
+                }
+            }
+            TouchFilterKind::Integrator { max } => {
+                if on {
+                    self.count = self.count.saturating_add(1).min(max);
+                } else {
+                    self.count = self.count.saturating_sub(1);
+                }
+                if self.count >= max {
+                    self.touched = true;
+                } else if self.count == 0 {
+                    self.touched = false;
+                }
+            }
+        }
+        self.touched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(filter: &mut TouchFilter, samples: &[bool]) -> Vec<bool> {
+        samples.iter().map(|on| filter.update(*on)).collect()
+    }
+
+    #[test]
+    fn majority() {
+        let mut filter = TouchFilter::new(TouchFilterKind::Majority { n: 3, m: 5 });
+        let states = feed(&mut filter, &[true, false, true, true, false, true]);
+        assert_eq!(states, [false, false, false, true, true, true]);
+        // released when n of the last m samples are off
+        let states = feed(&mut filter, &[true, false, false, false]);
+        assert_eq!(states, [true, true, false, false]);
+    }
+
+    #[test]
+    fn majority_ignores_single_glitches() {
+        let mut filter = TouchFilter::new(TouchFilterKind::Majority { n: 2, m: 3 });
+        let states = feed(&mut filter, &[true, false, false, true, false, false]);
+        assert!(states.iter().all(|touched| !touched));
+    }
+
+    #[test]
+    fn majority_of_32() {
+        let mut filter = TouchFilter::new(TouchFilterKind::Majority { n: 32, m: 32 });
+        assert!(!feed(&mut filter, &[true; 31])
+            .iter()
+            .any(|touched| *touched));
+        assert!(filter.update(true));
+        assert!(filter.update(false));
+    }
+
+    #[test]
+    fn integrator() {
+        let mut filter = TouchFilter::new(TouchFilterKind::Integrator { max: 3 });
+        let states = feed(&mut filter, &[true, true, false, true, true]);
+        assert_eq!(states, [false, false, false, false, true]);
+        // the count is capped at max, so the release takes max samples
+        let states = feed(&mut filter, &[true, true, false, false, true, false, false]);
+        assert_eq!(states, [true, true, true, true, true, true, false]);
+    }
+
+    #[test]
+    fn reset() {
+        let mut filter = TouchFilter::new(TouchFilterKind::Integrator { max: 1 });
+        assert!(filter.update(true));
+        filter.reset();
+        assert!(!filter.is_touched());
+    }
+}
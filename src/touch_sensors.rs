@@ -1,9 +1,14 @@
-use defmt::{debug, info, write, Format, Formatter};
-
-use embassy_rp::gpio::{Flex, Pull};
+use defmt::{debug, info, warn, write, Format, Formatter};
 use embassy_time::{Duration, Instant, Timer};
 
+use crate::calibration_stats::{quality, Histogram};
 use crate::config::*;
+use crate::crosstalk::Crosstalk;
+use crate::hum::{HumDetector, HumReport, SampleScheduler};
+use crate::math::isqrt;
+use crate::sensor_health::{SensorFault, SensorHealth};
+use crate::touch_filter::TouchFilter;
+use crate::touch_source::TouchSource;
 
 #[derive(Default, Clone, Copy, Format, PartialEq)]
 pub enum CalibrationStatus {
@@ -18,6 +23,8 @@ pub struct CalibrationData {
     pub min_time: Duration,
     pub max_time: Duration,
     pub status: CalibrationStatus,
+    // permille, see `calibration_stats::quality()`, None if not known
+    pub quality: Option<u16>,
 }
 
 impl Default for CalibrationData {
@@ -26,6 +33,7 @@ impl Default for CalibrationData {
             min_time: Duration::MAX,
             max_time: Duration::MIN,
             status: CalibrationStatus::NA,
+            quality: None,
         }
     }
 }
@@ -56,6 +64,9 @@ impl Format for CalibrationDataSet {
                 write!(f, "{}", pin_data.max_time.as_micros());
             }
             write!(f, "]");
+            if let Some(quality) = pin_data.quality {
+                write!(f, "q{}", quality);
+            }
         }
     }
 }
@@ -66,99 +77,229 @@ pub enum TouchSensorStatus {
     NA,
     On,
     Off,
+    // misbehaving while playing, disabled until it recovers
+    Broken,
 }
 
-pub struct TouchSensors<'a> {
-    pins: [Flex<'a>; NUM_SENSORS],
-    calibration: CalibrationDataSet,
-    threshold: Duration,
+/// Hardware measuring the sensor discharge times
+#[allow(async_fn_in_trait)]
+pub trait DischargeTimer {
+    /// Charge the pins in the `pins` bit mask and measure how long each one
+    /// takes to discharge. The other pins are driven low meanwhile, as guards,
+    /// their results are `None`.
+    ///
+    /// `None` means the pin has not discharged within `limit`.
+    async fn measure_until(
+        &mut self,
+        limit: Duration,
+        pins: u32,
+    ) -> [Option<Duration>; NUM_SENSORS];
+
+    /// Charge the pins in the `pins` bit mask and check which are still
+    /// charged at their thresholds, the other pins are reported as not charged.
+    ///
+    /// `order` lists pin indices sorted by threshold.
+    async fn sample(
+        &mut self,
+        thresholds: &[Duration; NUM_SENSORS],
+        order: &[usize; NUM_SENSORS],
+        pins: u32,
+    ) -> [bool; NUM_SENSORS] {
+        let limit = order
+            .iter()
+            .rev()
+            .find(|&&i| pins & (1 << i) != 0)
+            .map_or(Duration::MIN, |&i| thresholds[i]);
+        let times = self.measure_until(limit, pins).await;
+        core::array::from_fn(|i| match times[i] {
+            Some(t) => t > thresholds[i],
+            None => pins & (1 << i) != 0,
+        })
+    }
 }
 
-impl<'a> TouchSensors<'a> {
-    pub fn new(mut pins: [Flex<'a>; NUM_SENSORS]) -> Self {
-        let threshold = Duration::from_micros(500);
+/// Pins scanned together and the pause before scanning them
+#[derive(Clone, Copy)]
+pub struct ScanGroup {
+    // bit mask of the pins
+    pub pins: u32,
+    // pause before the scan, for the pins of the previous group to discharge;
+    // they are left as inputs, the guards are driven low only during a scan
+    pub settle: Duration,
+}
+
+const ALL_PINS: u32 = (1 << NUM_SENSORS) - 1;
+
+static ALL_GROUPS: [ScanGroup; 1] = [ScanGroup {
+    pins: ALL_PINS,
+    settle: Duration::from_ticks(0),
+}];
 
-        for pin in &mut pins {
-            pin.set_pull(Pull::None);
+static INTERLEAVED_GROUPS: [ScanGroup; 2] = [
+    ScanGroup {
+        pins: ALL_PINS & 0x5555_5555,
+        settle: SCAN_GROUP_SETTLE,
+    },
+    ScanGroup {
+        pins: ALL_PINS & 0xAAAA_AAAA,
+        settle: SCAN_GROUP_SETTLE,
+    },
+];
+
+/// Every pin is in exactly one of `groups`, a pin in none would never be scanned
+const fn covers_each_pin_once(groups: &[ScanGroup]) -> bool {
+    let mut seen = 0;
+    let mut i = 0;
+    while i < groups.len() {
+        if groups[i].pins & seen != 0 {
+            return false;
         }
+        seen |= groups[i].pins;
+        i += 1;
+    }
+    seen == ALL_PINS
+}
+
+const _: () = if let ScanStrategy::Groups(groups) = SCAN_STRATEGY {
+    assert!(
+        covers_each_pin_once(groups),
+        "SCAN_STRATEGY groups must cover each pin exactly once"
+    );
+};
+
+fn scan_groups() -> &'static [ScanGroup] {
+    match SCAN_STRATEGY {
+        ScanStrategy::All => &ALL_GROUPS,
+        ScanStrategy::Interleaved => &INTERLEAVED_GROUPS,
+        ScanStrategy::Groups(groups) => groups,
+    }
+}
+
+pub struct TouchSensors<T: DischargeTimer> {
+    timer: T,
+    calibration: CalibrationDataSet,
+    // discharge times seen during calibration
+    stats: [Histogram; NUM_SENSORS],
+    // thresholds for touching and releasing, with hysteresis between them
+    thresholds: [Duration; NUM_SENSORS],
+    off_thresholds: [Duration; NUM_SENSORS],
+    filters: [TouchFilter; NUM_SENSORS],
+    crosstalk: Crosstalk,
+    health: [SensorHealth; NUM_SENSORS],
+    // measure full discharge times in take_sample(), not only compare with thresholds
+    analog: bool,
+    last_times: [Option<Duration>; NUM_SENSORS],
+    sensitivity: u32,
+    // slowly filtered idle discharge times (in 1/256 ticks) and their values
+    // at the start of tracking, the difference is the baseline drift
+    baselines: [Option<(i64, i64)>; NUM_SENSORS],
+    baseline_updated: Instant,
+    hum: HumDetector,
+    scheduler: SampleScheduler,
+    // the next sample is at a random phase of the interference, for its detection
+    hum_sample: bool,
+}
+
+fn shift(time: Duration, ticks: i64) -> Duration {
+    if ticks >= 0 {
+        time + Duration::from_ticks(ticks as u64)
+    } else {
+        time.checked_sub(Duration::from_ticks(ticks.unsigned_abs()))
+            .unwrap_or(Duration::MIN)
+    }
+}
+
+impl<T: DischargeTimer> TouchSensors<T> {
+    pub fn new(timer: T) -> Self {
+        let threshold = Duration::from_micros(500);
 
         Self {
-            pins,
+            timer,
             calibration: Default::default(),
-            threshold,
+            stats: [Histogram::new(); NUM_SENSORS],
+            thresholds: [threshold; NUM_SENSORS],
+            off_thresholds: [threshold; NUM_SENSORS],
+            filters: [TouchFilter::new(TOUCH_FILTER); NUM_SENSORS],
+            crosstalk: Crosstalk::new(),
+            health: [SensorHealth::new(Instant::now()); NUM_SENSORS],
+            analog: false,
+            last_times: [None; NUM_SENSORS],
+            sensitivity: 500,
+            baselines: [None; NUM_SENSORS],
+            baseline_updated: Instant::now(),
+            hum: HumDetector::new(),
+            scheduler: SampleScheduler::new(SAMPLE_TIMING),
+            hum_sample: false,
         }
     }
     pub async fn calibrate_start(&mut self) {
         info!("Calibration start");
         self.calibration = Default::default();
+        for stats in &mut self.stats {
+            stats.reset();
+        }
+        self.baselines = [None; NUM_SENSORS];
+        self.crosstalk.reset();
+        self.health = [SensorHealth::new(Instant::now()); NUM_SENSORS];
+        for filter in &mut self.filters {
+            filter.reset();
+        }
     }
     pub async fn calibrate_step(&mut self) -> CalibrationDataSet {
         info!("Calibration step");
 
-        for pin in &mut self.pins {
-            pin.set_as_output();
-            pin.set_high();
-        }
-
-        Timer::after(Duration::from_micros(1)).await;
+        let times = self.measure_until(CALIBRATION_STEP_TIME).await;
 
-        let t0 = Instant::now();
+        let mut all_c = CalibrationData::default();
+        for (i, time) in times.iter().enumerate() {
+            self.stats[i].add(*time);
+            let Some(clusters) = self.stats[i].analyze() else {
+                continue;
+            };
+            let pin_c = &mut self.calibration.pins[i];
+            let idle = clusters.idle;
+            *pin_c = CalibrationData {
+                min_time: idle.mean,
+                max_time: idle.mean + idle.sigma,
+                ..Default::default()
+            };
+            if idle.mean < MIN_TIME_REQUIRED {
+                pin_c.status = CalibrationStatus::Bad;
+            } else if let Some(touched) = clusters.touched {
+                // put the threshold between the clusters, for the default sensitivity
+                pin_c.max_time = clusters.split + (clusters.split - idle.mean);
+                pin_c.status = CalibrationStatus::Ok;
+                pin_c.quality = Some(quality(
+                    touched.mean - idle.mean,
+                    idle.sigma + touched.sigma,
+                ));
 
-        for pin in &mut self.pins {
-            pin.set_as_input();
+                // get those only from reasonable-behaving pins
+                all_c.min_time = all_c.min_time.min(pin_c.min_time);
+                all_c.max_time = all_c.max_time.max(pin_c.max_time);
+            }
         }
+        self.calibration.all = all_c;
 
-        let mut already_done: [bool; NUM_SENSORS] = [false; NUM_SENSORS];
-        let mut num_done = 0;
-        let all_c = &mut self.calibration.all;
-        loop {
-            Timer::after(Duration::from_micros(1)).await;
-            let t = Instant::elapsed(&t0);
-            let done: [bool; NUM_SENSORS] = core::array::from_fn(|i| self.pins[i].is_low());
-
-            for i in 0..NUM_SENSORS {
-                if already_done[i] {
-                    continue;
-                }
-                if done[i] {
-                    let pin_c = &mut self.calibration.pins[i];
-                    if t < pin_c.min_time {
-                        pin_c.min_time = t;
-                    }
-                    if t > pin_c.max_time {
-                        pin_c.max_time = t;
-                    }
-                    if pin_c.min_time < MIN_TIME_REQUIRED {
-                        pin_c.status = CalibrationStatus::Bad;
-                    } else if pin_c.max_time - pin_c.min_time >= MIN_MARGIN_REQUIRED {
-                        pin_c.status = CalibrationStatus::Ok;
-
-                        // get those only from reasonable-behaving pins
-                        if pin_c.min_time < all_c.min_time {
-                            all_c.min_time = t;
-                        }
-                        if pin_c.max_time > all_c.max_time {
-                            all_c.max_time = t;
-                        }
-                    }
-                    already_done[i] = true;
-                    num_done += 1;
+        if all_c.max_time > all_c.min_time {
+            // we have some usable data, use it to find some bad sensors
+            for pin_c in &mut self.calibration.pins {
+                if pin_c.min_time > all_c.max_time {
+                    pin_c.status = CalibrationStatus::Bad;
                 }
             }
+        }
 
-            if all_c.max_time > all_c.min_time {
-                // we have some usable data, use it to find some bad sensors
-                for i in 0..NUM_SENSORS {
-                    let pin_c = &mut self.calibration.pins[i];
-                    if pin_c.min_time > all_c.max_time {
-                        pin_c.status = CalibrationStatus::Bad;
-                    }
+        if CROSSTALK_SUPPRESSION {
+            let rises = core::array::from_fn(|i| {
+                let pin_c = &self.calibration.pins[i];
+                if pin_c.status == CalibrationStatus::Bad {
+                    return None;
                 }
-            }
-
-            if num_done == NUM_SENSORS || t >= CALIBRATION_STEP_TIME {
-                break;
-            };
+                let t = times[i].unwrap_or(CALIBRATION_STEP_TIME);
+                t.checked_sub(pin_c.min_time).map(|rise| rise.as_ticks())
+            });
+            self.crosstalk.learn(&rises);
         }
 
         self.calibration
@@ -192,43 +333,361 @@ impl<'a> TouchSensors<'a> {
                     self.calibration.all.min_time = max_min;
                     self.calibration.all.max_time = min_max;
                 };
-                self.threshold = self.calibration.all.min_time + self.calibration.all.max_time / 2;
                 self.calibration.status = CalibrationStatus::Ok;
             }
             (_, _) => {
                 info!("Bad calibration no single good pin");
-                self.threshold = CALIBRATION_STEP_TIME / 2;
+                self.calibration.all.min_time = Duration::MIN;
+                self.calibration.all.max_time = CALIBRATION_STEP_TIME;
                 self.calibration.status = CalibrationStatus::Bad;
             }
         }
+        self.finish_calibration()
+    }
+    fn finish_calibration(&mut self) -> CalibrationDataSet {
+        self.calibration.all.status = self.calibration.status;
+        self.set_sensitivity(500);
         info!(
-            "status: {}, threshold: {}",
+            "status: {}, thresholds: {}",
             self.calibration.status,
-            self.threshold.as_micros()
+            self.thresholds.map(|t| t.as_micros())
         );
-        self.calibration.all.status = self.calibration.status;
+        info!("crosstalk: {}", self.crosstalk);
         self.calibration
     }
-    pub fn set_sensitivity(&mut self, permile: u32) {
-        let range = self.calibration.all.max_time - self.calibration.all.min_time;
-        self.threshold = self.calibration.all.min_time + range * (1000 - permile) / 1000;
-    }
-    pub async fn take_sample(&mut self) -> [TouchSensorStatus; NUM_SENSORS] {
-        for pin in &mut self.pins {
-            pin.set_as_output();
-            pin.set_high();
-        }
+    /// Calibrate from idle readings only, no sensor may be touched meanwhile.
+    ///
+    /// Discharge times are measured for `duration` and each threshold is put
+    /// above the noise floor of the sensor.
+    pub async fn calibrate_idle(&mut self, duration: Duration) -> CalibrationDataSet {
+        info!("Idle calibration start");
+        self.calibrate_start().await;
 
-        Timer::after(Duration::from_micros(1)).await;
+        let mut count = [0u32; NUM_SENSORS];
+        let mut sum = [0u64; NUM_SENSORS];
+        let mut sum_sq = [0u64; NUM_SENSORS];
+        let mut num_samples = 0u32;
 
         let t0 = Instant::now();
+        while t0.elapsed() < duration {
+            let hum_sample = self.scheduler.wait(&self.hum.report()).await;
+            let now = Instant::now();
+            let times = self.measure_until(CALIBRATION_STEP_TIME).await;
+            if hum_sample {
+                self.feed_hum(now, &times, &[false; NUM_SENSORS]);
+            }
+            num_samples += 1;
+            for (i, time) in times.iter().enumerate() {
+                let t = match time {
+                    Some(t) => *t,
+                    None => continue,
+                };
+                let pin_c = &mut self.calibration.pins[i];
+                pin_c.min_time = pin_c.min_time.min(t);
+                pin_c.max_time = pin_c.max_time.max(t);
+                count[i] += 1;
+                sum[i] += t.as_ticks();
+                sum_sq[i] += t.as_ticks() * t.as_ticks();
+            }
+            Timer::after(AUTO_CALIBRATION_INTERVAL).await;
+        }
 
-        for pin in &mut self.pins {
-            pin.set_as_input();
+        let all_c = &mut self.calibration.all;
+        for i in 0..NUM_SENSORS {
+            let pin_c = &mut self.calibration.pins[i];
+            if count[i] == 0 || count[i] < num_samples || pin_c.min_time < MIN_TIME_REQUIRED {
+                // shorted, disconnected or touched all the time
+                pin_c.status = CalibrationStatus::Bad;
+                continue;
+            }
+            let n = count[i] as u64;
+            let mean = sum[i] / n;
+            let variance = (sum_sq[i] / n).saturating_sub(mean * mean);
+            let sigma = isqrt(variance.min(u32::MAX as u64) as u32) as u64;
+
+            let threshold = Duration::from_ticks(mean + sigma * AUTO_CALIBRATION_SIGMAS)
+                .max(pin_c.max_time + AUTO_CALIBRATION_MARGIN);
+
+            // put the threshold in the middle of the window, for the default sensitivity
+            pin_c.max_time = threshold + (threshold - pin_c.min_time);
+            pin_c.status = CalibrationStatus::Ok;
+            // how well the smallest acceptable touch would stand out of the noise
+            pin_c.quality = Some(quality(MIN_MARGIN_REQUIRED, Duration::from_ticks(sigma)));
+
+            all_c.min_time = all_c.min_time.min(pin_c.min_time);
+            all_c.max_time = all_c.max_time.max(pin_c.max_time);
         }
 
-        Timer::at(t0 + self.threshold).await;
-        let on: [bool; NUM_SENSORS] = core::array::from_fn(|i| self.pins[i].is_high());
+        self.calibration.status = if all_c.max_time > all_c.min_time {
+            CalibrationStatus::Ok
+        } else {
+            info!("Bad idle calibration no single good pin");
+            all_c.min_time = Duration::MIN;
+            all_c.max_time = CALIBRATION_STEP_TIME;
+            CalibrationStatus::Bad
+        };
+        self.finish_calibration()
+    }
+    /// Baseline drift of pin `i` since calibration, in ticks
+    fn drift(&self, i: usize) -> i64 {
+        match self.baselines[i] {
+            Some((baseline, reference)) => (baseline - reference) >> 8,
+            None => 0,
+        }
+    }
+    /// Range of discharge times (untouched..touched) the threshold of a pin is chosen from.
+    fn threshold_window(&self, i: usize) -> (Duration, Duration) {
+        let (min_time, max_time) = self.calibrated_window(i);
+        let drift = self.drift(i);
+        (shift(min_time, drift), shift(max_time, drift))
+    }
+    fn calibrated_window(&self, i: usize) -> (Duration, Duration) {
+        let all_c = &self.calibration.all;
+        let pin_c = &self.calibration.pins[i];
+        match pin_c.status {
+            // touched during calibration, it has its own range
+            CalibrationStatus::Ok => (pin_c.min_time, pin_c.max_time),
+            // not touched, but we know its idle time, borrow the range width from the good pins
+            CalibrationStatus::NA if pin_c.min_time < Duration::MAX => (
+                pin_c.min_time,
+                pin_c.min_time
+                    + all_c
+                        .max_time
+                        .checked_sub(all_c.min_time)
+                        .unwrap_or(Duration::MIN),
+            ),
+            _ => (all_c.min_time, all_c.max_time),
+        }
+    }
+    pub fn set_sensitivity(&mut self, permile: u32) {
+        self.sensitivity = permile.min(1000);
+        self.update_thresholds();
+    }
+    fn update_thresholds(&mut self) {
+        let permile = self.sensitivity;
+        for i in 0..NUM_SENSORS {
+            let (min_time, max_time) = self.threshold_window(i);
+            let range = max_time.checked_sub(min_time).unwrap_or(Duration::MIN);
+            self.thresholds[i] = min_time + range * (1000 - permile) / 1000;
+            self.off_thresholds[i] = self.thresholds[i]
+                .checked_sub(range * TOUCH_HYSTERESIS / 1000)
+                .unwrap_or(Duration::MIN)
+                .max(min_time);
+        }
+    }
+    /// Threshold to compare pin `i` with, depending on whether it is touched now
+    fn active_threshold(&self, i: usize) -> Duration {
+        if self.filters[i].is_touched() {
+            self.off_thresholds[i]
+        } else {
+            self.thresholds[i]
+        }
+    }
+    /// Measure the discharge times, group by group (see `SCAN_STRATEGY`)
+    async fn measure_until(&mut self, limit: Duration) -> [Option<Duration>; NUM_SENSORS] {
+        let mut times = [None; NUM_SENSORS];
+        for group in scan_groups() {
+            if group.settle > Duration::MIN {
+                Timer::after(group.settle).await;
+            }
+            let group_times = self.timer.measure_until(limit, group.pins).await;
+            for (i, time) in times.iter_mut().enumerate() {
+                if group.pins & (1 << i) != 0 {
+                    *time = group_times[i];
+                }
+            }
+        }
+        times
+    }
+    /// Discharge time after which every pin is considered fully touched
+    fn measure_limit(&self) -> Duration {
+        (0..NUM_SENSORS)
+            .map(|i| self.threshold_window(i).1.max(self.thresholds[i]))
+            .max()
+            .unwrap_or(CALIBRATION_STEP_TIME)
+            .min(CALIBRATION_STEP_TIME)
+    }
+    /// Measure the discharge time of every pin.
+    ///
+    /// `None` means the pin has not discharged within the longest calibrated
+    /// 'touched' time, so it is definitely touched.
+    pub async fn measure(&mut self) -> [Option<Duration>; NUM_SENSORS] {
+        let limit = self.measure_limit();
+        self.measure_until(limit).await
+    }
+    /// Normalize a discharge time of pin `i` to its calibrated range, in permile:
+    /// 0 for an untouched pin, 1000 for a fully touched one.
+    pub fn level(&self, i: usize, time: Option<Duration>) -> u32 {
+        let (min_time, max_time) = self.threshold_window(i);
+        let time = match time {
+            Some(time) => time,
+            None => return 1000,
+        };
+        if time <= min_time {
+            0
+        } else if time >= max_time {
+            1000
+        } else {
+            ((time - min_time).as_ticks() * 1000 / (max_time - min_time).as_ticks()) as u32
+        }
+    }
+    /// Measure all the pins and return their normalized levels (see `level()`).
+    #[allow(dead_code)]
+    pub async fn measure_levels(&mut self) -> [u32; NUM_SENSORS] {
+        let times = self.measure().await;
+        core::array::from_fn(|i| self.level(i, times[i]))
+    }
+    /// Enable measuring of the full discharge times in `take_sample()` and `run()`,
+    /// so `levels()` can be used. This makes each sample take a bit longer.
+    pub fn set_analog(&mut self, analog: bool) {
+        self.analog = analog;
+        self.last_times = [None; NUM_SENSORS];
+    }
+    /// Normalized levels (see `level()`) from the last sample taken in analog mode.
+    pub fn levels(&self) -> [u32; NUM_SENSORS] {
+        if !self.analog {
+            return [0; NUM_SENSORS];
+        }
+        core::array::from_fn(|i| self.level(i, self.last_times[i]))
+    }
+    /// Normalized level (see `level()`) of the current threshold of pin `i`.
+    pub fn threshold_level(&self, i: usize) -> u32 {
+        self.level(i, Some(self.thresholds[i]))
+    }
+    async fn sample_digital(&mut self) -> [bool; NUM_SENSORS] {
+        let thresholds: [Duration; NUM_SENSORS] =
+            core::array::from_fn(|i| self.active_threshold(i));
+        let mut order: [usize; NUM_SENSORS] = core::array::from_fn(|i| i);
+        order.sort_unstable_by_key(|&i| thresholds[i]);
+        let mut on = [false; NUM_SENSORS];
+        for group in scan_groups() {
+            if group.settle > Duration::MIN {
+                Timer::after(group.settle).await;
+            }
+            let group_on = self.timer.sample(&thresholds, &order, group.pins).await;
+            for (i, on) in on.iter_mut().enumerate() {
+                if group.pins & (1 << i) != 0 {
+                    *on = group_on[i];
+                }
+            }
+        }
+        on
+    }
+    /// Crosstalk learned during the last calibration
+    #[allow(dead_code)]
+    pub fn crosstalk(&self) -> &Crosstalk {
+        &self.crosstalk
+    }
+    fn suppress_crosstalk(
+        &self,
+        times: &[Option<Duration>; NUM_SENSORS],
+        on: &mut [bool; NUM_SENSORS],
+    ) {
+        let limit = self.measure_limit();
+        let rises = core::array::from_fn(|i| {
+            let t = times[i].unwrap_or(limit);
+            t.checked_sub(self.threshold_window(i).0)
+                .map_or(0, |rise| rise.as_ticks())
+        });
+        self.crosstalk.suppress(&rises, on);
+    }
+    /// Feed the mean idle discharge time of the untouched pins to the hum detector
+    fn feed_hum(
+        &mut self,
+        time: Instant,
+        times: &[Option<Duration>; NUM_SENSORS],
+        on: &[bool; NUM_SENSORS],
+    ) {
+        let mut sum = 0;
+        let mut count = 0;
+        for i in 0..NUM_SENSORS {
+            if let (Some(t), false) = (times[i], on[i]) {
+                if self.calibration.pins[i].status != CalibrationStatus::Bad {
+                    sum += t.as_ticks();
+                    count += 1;
+                }
+            }
+        }
+        if let Some(mean) = sum.checked_div(count) {
+            self.hum.add(time, mean);
+        }
+    }
+    /// Interference detected in the idle readings
+    #[allow(dead_code)]
+    pub fn hum(&self) -> HumReport {
+        self.hum.report()
+    }
+    async fn sample_analog(&mut self, hum_sample: bool) -> [bool; NUM_SENSORS] {
+        let now = Instant::now();
+        let times = self.measure().await;
+        self.last_times = times;
+        let mut on = core::array::from_fn(|i| match times[i] {
+            Some(t) => t > self.active_threshold(i),
+            None => true,
+        });
+        if CROSSTALK_SUPPRESSION {
+            self.suppress_crosstalk(&times, &mut on);
+        }
+        for (health, time) in self.health.iter_mut().zip(times.iter()) {
+            health.analog_sample(*time);
+        }
+        if BASELINE_TRACKING {
+            self.track_baseline(&times, &on);
+        }
+        if hum_sample {
+            self.feed_hum(now, &times, &on);
+        }
+        on
+    }
+    /// Follow slow changes of the idle discharge times (humidity, drying fruits)
+    /// and shift the threshold windows accordingly.
+    fn track_baseline(
+        &mut self,
+        times: &[Option<Duration>; NUM_SENSORS],
+        on: &[bool; NUM_SENSORS],
+    ) {
+        self.baseline_updated = Instant::now();
+        if self.calibration.status != CalibrationStatus::Ok {
+            return;
+        }
+        for i in 0..NUM_SENSORS {
+            if on[i]
+                || self.filters[i].is_touched()
+                || self.calibration.pins[i].status == CalibrationStatus::Bad
+            {
+                continue;
+            }
+            let t = match times[i] {
+                Some(t) => t,
+                None => continue,
+            };
+            // only clearly untouched samples, so a held key is not absorbed into the baseline
+            if self.level(i, Some(t)) > BASELINE_MAX_LEVEL {
+                continue;
+            }
+            let (min_time, max_time) = self.calibrated_window(i);
+            let max_drift = ((max_time.as_ticks() as i64 - min_time.as_ticks() as i64)
+                * BASELINE_MAX_DRIFT as i64
+                / 1000)
+                << 8;
+            let value = (t.as_ticks() as i64) << 8;
+            let (baseline, reference) = self.baselines[i].get_or_insert((value, value));
+            *baseline += (value - *baseline) / BASELINE_FILTER_DIV;
+            *baseline = (*baseline).clamp(*reference - max_drift, *reference + max_drift);
+        }
+        self.update_thresholds();
+    }
+    pub async fn take_sample(&mut self) -> [TouchSensorStatus; NUM_SENSORS] {
+        let baseline_due =
+            BASELINE_TRACKING && self.baseline_updated.elapsed() >= BASELINE_UPDATE_INTERVAL;
+        // telling crosstalk from real touches needs the analog values
+        let crosstalk = CROSSTALK_SUPPRESSION && self.crosstalk.is_significant();
+        let hum_sample = core::mem::take(&mut self.hum_sample);
+        let on = if self.analog || baseline_due || crosstalk || hum_sample {
+            self.sample_analog(hum_sample).await
+        } else {
+            self.sample_digital().await
+        };
 
         core::array::from_fn(|i| match (self.calibration.pins[i].status, on[i]) {
             (CalibrationStatus::Ok, true) => TouchSensorStatus::On,
@@ -239,17 +698,106 @@ impl<'a> TouchSensors<'a> {
         })
     }
     pub async fn run(&mut self) -> [TouchSensorStatus; NUM_SENSORS] {
-        let mut result = self.take_sample().await;
-        debug!("sample: {}", result);
-        for _i in 0..(SENSOR_SAMPLES - 1) {
-            let new_sample = self.take_sample().await;
-            debug!("sample: {}", new_sample);
-            for (fin, cur) in result.iter_mut().zip(new_sample.iter()) {
-                if *fin != *cur {
-                    *fin = TouchSensorStatus::NA;
+        for _i in 0..SENSOR_SAMPLES {
+            self.hum_sample = self.scheduler.wait(&self.hum.report()).await;
+            let sample = self.take_sample().await;
+            debug!("sample: {}", sample);
+            for (filter, cur) in self.filters.iter_mut().zip(sample.iter()) {
+                match cur {
+                    TouchSensorStatus::On => filter.update(true),
+                    TouchSensorStatus::Off => filter.update(false),
+                    TouchSensorStatus::NA | TouchSensorStatus::Broken => continue,
+                };
+            }
+        }
+        let now = Instant::now();
+        core::array::from_fn(|i| {
+            if self.calibration.pins[i].status == CalibrationStatus::Bad {
+                return TouchSensorStatus::NA;
+            }
+            let touched = self.filters[i].is_touched();
+            let was_broken = self.health[i].fault().is_some();
+            match self.health[i].update(touched, now) {
+                Some(fault) => {
+                    if !was_broken {
+                        warn!("Sensor {} disabled: {}", i, fault);
+                    }
+                    TouchSensorStatus::Broken
+                }
+                None => {
+                    if was_broken {
+                        info!("Sensor {} recovered", i);
+                    }
+                    if touched {
+                        TouchSensorStatus::On
+                    } else {
+                        TouchSensorStatus::Off
+                    }
                 }
             }
+        })
+    }
+    /// Faults detected while playing
+    #[allow(dead_code)]
+    pub fn faults(&self) -> [Option<SensorFault>; NUM_SENSORS] {
+        core::array::from_fn(|i| self.health[i].fault())
+    }
+}
+
+impl<T: DischargeTimer> TouchSource for TouchSensors<T> {
+    async fn calibrate_start(&mut self) {
+        TouchSensors::calibrate_start(self).await
+    }
+    async fn calibrate_step(&mut self) -> CalibrationDataSet {
+        TouchSensors::calibrate_step(self).await
+    }
+    async fn calibrate_stop(&mut self) -> CalibrationDataSet {
+        TouchSensors::calibrate_stop(self).await
+    }
+    async fn calibrate_idle(&mut self, duration: Duration) -> CalibrationDataSet {
+        TouchSensors::calibrate_idle(self, duration).await
+    }
+    fn set_sensitivity(&mut self, permile: u32) {
+        TouchSensors::set_sensitivity(self, permile)
+    }
+    fn set_analog(&mut self, analog: bool) {
+        TouchSensors::set_analog(self, analog)
+    }
+    async fn run(&mut self) -> [TouchSensorStatus; NUM_SENSORS] {
+        TouchSensors::run(self).await
+    }
+    fn levels(&self) -> [u32; NUM_SENSORS] {
+        TouchSensors::levels(self)
+    }
+    fn threshold_level(&self, i: usize) -> u32 {
+        TouchSensors::threshold_level(self, i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(pins: u32) -> ScanGroup {
+        ScanGroup {
+            pins,
+            settle: SCAN_GROUP_SETTLE,
         }
-        result
+    }
+
+    #[test]
+    fn scan_groups_cover_each_pin_once() {
+        assert!(covers_each_pin_once(&ALL_GROUPS));
+        assert!(covers_each_pin_once(&INTERLEAVED_GROUPS));
+        assert!(covers_each_pin_once(&[
+            group(ALL_PINS & 0x00FF),
+            group(ALL_PINS & !0x00FF)
+        ]));
+        // pin 0 never scanned
+        assert!(!covers_each_pin_once(&[group(ALL_PINS & !1)]));
+        // pin 0 scanned twice
+        assert!(!covers_each_pin_once(&[group(ALL_PINS), group(1)]));
+        // no such pin
+        assert!(!covers_each_pin_once(&[group(ALL_PINS | 1 << NUM_SENSORS)]));
     }
 }
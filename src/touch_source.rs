@@ -0,0 +1,104 @@
+// Sensing interface the keyboard logic is driven by
+
+use crate::config::*;
+use crate::touch_sensors::{CalibrationDataSet, CalibrationStatus, TouchSensorStatus};
+
+/// Source of touch sensor states and levels (permile, see `TouchSensors::level()`)
+#[allow(async_fn_in_trait)]
+pub trait TouchSource {
+    async fn calibrate_start(&mut self);
+    async fn calibrate_step(&mut self) -> CalibrationDataSet;
+    async fn calibrate_stop(&mut self) -> CalibrationDataSet;
+    /// Calibrate from idle readings only, no sensor may be touched meanwhile.
+    async fn calibrate_idle(&mut self, duration: embassy_time::Duration) -> CalibrationDataSet;
+    fn set_sensitivity(&mut self, permile: u32);
+    /// Enable measuring of the analog levels, see `levels()`
+    fn set_analog(&mut self, analog: bool);
+    /// Scan all the sensors
+    async fn run(&mut self) -> [TouchSensorStatus; NUM_SENSORS];
+    /// Levels measured in the last scan, zeros when not in analog mode
+    fn levels(&self) -> [u32; NUM_SENSORS];
+    /// Level of the current threshold of sensor `i`
+    fn threshold_level(&self, i: usize) -> u32;
+}
+
+/// Touch source driven by the code, for running the keyboard logic without
+/// the sensor hardware.
+#[allow(dead_code)]
+pub struct SimulatedTouchSource {
+    pub status: [TouchSensorStatus; NUM_SENSORS],
+    pub levels: [u32; NUM_SENSORS],
+    pub sensitivity: u32,
+    analog: bool,
+}
+
+impl Default for SimulatedTouchSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(dead_code)]
+impl SimulatedTouchSource {
+    pub fn new() -> Self {
+        Self {
+            status: [TouchSensorStatus::Off; NUM_SENSORS],
+            levels: [0; NUM_SENSORS],
+            sensitivity: 500,
+            analog: false,
+        }
+    }
+    /// Touch (`level` above the threshold) or release (`level` below) sensor `i`
+    pub fn set_level(&mut self, i: usize, level: u32) {
+        self.levels[i] = level.min(1000);
+        self.status[i] = if level > self.threshold_level(i) {
+            TouchSensorStatus::On
+        } else {
+            TouchSensorStatus::Off
+        };
+    }
+    fn calibration(&self) -> CalibrationDataSet {
+        let mut calibration = CalibrationDataSet::default();
+        for (pin_c, status) in calibration.pins.iter_mut().zip(self.status.iter()) {
+            pin_c.status = match status {
+                TouchSensorStatus::NA | TouchSensorStatus::Broken => CalibrationStatus::Bad,
+                _ => CalibrationStatus::Ok,
+            };
+        }
+        calibration.status = CalibrationStatus::Ok;
+        calibration.all.status = CalibrationStatus::Ok;
+        calibration
+    }
+}
+
+impl TouchSource for SimulatedTouchSource {
+    async fn calibrate_start(&mut self) {}
+    async fn calibrate_step(&mut self) -> CalibrationDataSet {
+        self.calibration()
+    }
+    async fn calibrate_stop(&mut self) -> CalibrationDataSet {
+        self.calibration()
+    }
+    async fn calibrate_idle(&mut self, _duration: embassy_time::Duration) -> CalibrationDataSet {
+        self.calibration()
+    }
+    fn set_sensitivity(&mut self, permile: u32) {
+        self.sensitivity = permile.min(1000);
+    }
+    fn set_analog(&mut self, analog: bool) {
+        self.analog = analog;
+    }
+    async fn run(&mut self) -> [TouchSensorStatus; NUM_SENSORS] {
+        self.status
+    }
+    fn levels(&self) -> [u32; NUM_SENSORS] {
+        if self.analog {
+            self.levels
+        } else {
+            [0; NUM_SENSORS]
+        }
+    }
+    fn threshold_level(&self, _i: usize) -> u32 {
+        1000 - self.sensitivity
+    }
+}
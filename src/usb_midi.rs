@@ -1,27 +1,55 @@
-use defmt::info;
+use defmt::{debug, info};
 
 use embassy_futures::join::join3;
 use embassy_futures::select::{select, Either};
 use embassy_usb::driver::EndpointError;
 
 use crate::board::{Irqs, MidiUsb};
-use crate::midi::{MidiChannelReceiver, MidiMsg};
+use crate::config::USB_MIDI_CABLES;
+use crate::midi::MidiChannelReceiver;
+use crate::midi_router::ConnectionSignal;
+use crate::usb_midi_packet::{UsbMidiDecoder, UsbMidiPacket, UsbMidiPackets};
 use static_cell::StaticCell;
 
 pub struct UsbMidi<'d> {
     usb: embassy_usb::UsbDevice<'d, embassy_rp::usb::Driver<'d, MidiUsb>>,
     midi_rx: MidiChannelReceiver<'d>,
+    connection: &'d ConnectionSignal,
     class_tx: embassy_usb::class::midi::Sender<'d, embassy_rp::usb::Driver<'d, MidiUsb>>,
     class_rx: embassy_usb::class::midi::Receiver<'d, embassy_rp::usb::Driver<'d, MidiUsb>>,
 }
 
+// move the packets of the pending messages to `buf`, as many as fit
+fn fill_packets(
+    buf: &mut [u8],
+    pos: &mut usize,
+    packets: &mut UsbMidiPackets,
+    midi_rx: &MidiChannelReceiver,
+) {
+    while *pos + 4 <= buf.len() {
+        if let Some(packet) = packets.next() {
+            buf[*pos..*pos + 4].copy_from_slice(&packet.0);
+            *pos += 4;
+        } else if let Ok(msg) = midi_rx.try_receive() {
+            info!("usb: msg: {}", msg);
+            *packets = UsbMidiPackets::encode(&msg, 0);
+        } else {
+            break;
+        }
+    }
+}
+
 static DEVICE_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
 static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
 static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
 static CONTROL_BUF: StaticCell<[u8; 256]> = StaticCell::new();
 
 impl<'d> UsbMidi<'d> {
-    pub fn new(usb_per: MidiUsb, midi_rx: MidiChannelReceiver<'d>) -> Self {
+    pub fn new(
+        usb_per: MidiUsb,
+        midi_rx: MidiChannelReceiver<'d>,
+        connection: &'d ConnectionSignal,
+    ) -> Self {
         let driver = embassy_rp::usb::Driver::new(usb_per, Irqs);
 
         // Create embassy-usb Config
@@ -54,7 +82,12 @@ impl<'d> UsbMidi<'d> {
             control_buf,
         );
 
-        let class = embassy_usb::class::midi::MidiClass::new(&mut builder, 1, 1, 64);
+        let class = embassy_usb::class::midi::MidiClass::new(
+            &mut builder,
+            USB_MIDI_CABLES as u8,
+            USB_MIDI_CABLES as u8,
+            64,
+        );
         let (class_tx, class_rx) = class.split();
 
         let usb = builder.build();
@@ -62,6 +95,7 @@ impl<'d> UsbMidi<'d> {
         Self {
             usb,
             midi_rx,
+            connection,
             class_tx,
             class_rx,
         }
@@ -80,38 +114,19 @@ impl<'d> UsbMidi<'d> {
                     };
                 }
                 info!("Connected");
+                self.connection.signal(true);
 
                 let mut pos = 0;
-
-                let add_msg = |buf: &mut [u8], pos: &mut usize, msg: MidiMsg| {
-                    info!("usb: msg: {}", msg);
-
-                    buf[*pos] = msg.usb_cin();
-                    let num_bytes = msg.serialize(&mut buf[*pos + 1..*pos + 4]);
-                    if num_bytes == 0 || num_bytes > 3 {
-                        return;
-                    }
-                    if num_bytes < 3 {
-                        buf[*pos + 3] = 0;
-                    }
-                    if num_bytes == 1 {
-                        buf[*pos + 2] = 0;
-                    }
-                    *pos += 4;
-                };
+                let mut packets = UsbMidiPackets::default();
 
                 loop {
+                    fill_packets(&mut buf, &mut pos, &mut packets, &self.midi_rx);
                     if pos == 0 {
                         // USB buffer empty
-                        add_msg(&mut buf, &mut pos, self.midi_rx.receive().await);
-                        while pos < 60 {
-                            if let Ok(msg) = self.midi_rx.try_receive() {
-                                add_msg(&mut buf, &mut pos, msg);
-                            } else {
-                                break;
-                            };
-                        }
-                    } else if pos >= 60 {
+                        let msg = self.midi_rx.receive().await;
+                        info!("usb: msg: {}", msg);
+                        packets = UsbMidiPackets::encode(&msg, 0);
+                    } else if pos + 4 > buf.len() {
                         // USB buffer full
                         info!("usb: sending: {} (full)", &buf[0..pos]);
                         match self.class_tx.write_packet(&buf[0..pos]).await {
@@ -131,14 +146,8 @@ impl<'d> UsbMidi<'d> {
                         .await
                         {
                             Either::First(msg) => {
-                                add_msg(&mut buf, &mut pos, msg);
-                                while pos < 60 {
-                                    if let Ok(msg) = self.midi_rx.try_receive() {
-                                        add_msg(&mut buf, &mut pos, msg);
-                                    } else {
-                                        break;
-                                    };
-                                }
+                                info!("usb: msg: {}", msg);
+                                packets = UsbMidiPackets::encode(&msg, 0);
                             }
                             Either::Second(Ok(_)) => {
                                 info!("sent!");
@@ -152,19 +161,29 @@ impl<'d> UsbMidi<'d> {
                     };
                 }
                 info!("Disconnected");
+                self.connection.signal(false);
             }
         };
 
         let midi_recv_task = async {
             let mut buf = [0; 64];
+            let mut decoder = UsbMidiDecoder::new();
             loop {
                 self.class_rx.wait_connection().await;
                 info!("Connected (recv)");
+                decoder.reset();
 
                 loop {
                     match self.class_rx.read_packet(&mut buf).await {
                         Ok(n) => {
-                            info!("Received {} bytes\n", n);
+                            debug!("Received {} bytes", n);
+                            for bytes in buf[..n].chunks_exact(4) {
+                                let mut packet = UsbMidiPacket::default();
+                                packet.0.copy_from_slice(bytes);
+                                for msg in decoder.decode(&packet).into_iter().flatten() {
+                                    info!("usb: received: {}", msg);
+                                }
+                            }
                         }
                         Err(EndpointError::BufferOverflow) => panic!("buffer overflow!"),
                         Err(EndpointError::Disabled) => break,
@@ -0,0 +1,389 @@
+// USB-MIDI 1.0 event packets: a header byte with the cable number and the
+// code index number (CIN), then up to 3 MIDI bytes padded with zeros
+
+use defmt::{debug, Format};
+
+use crate::config::*;
+use crate::midi::{MidiMsg, MidiParser};
+
+#[derive(Clone, Copy, Format, PartialEq, Default)]
+pub struct UsbMidiPacket(pub [u8; 4]);
+
+#[allow(dead_code)]
+impl UsbMidiPacket {
+    /// Packet for virtual cable `cable` (0..=15) carrying up to 3 `bytes`
+    pub fn new(cable: u8, cin: u8, bytes: &[u8]) -> Self {
+        let mut packet = [(cable & 0x0F) << 4 | cin & 0x0F, 0, 0, 0];
+        let len = bytes.len().min(3);
+        packet[1..1 + len].copy_from_slice(&bytes[..len]);
+        Self(packet)
+    }
+    pub fn cable(&self) -> u8 {
+        self.0[0] >> 4
+    }
+    pub fn cin(&self) -> u8 {
+        self.0[0] & 0x0F
+    }
+    /// The MIDI bytes carried, without the padding
+    pub fn midi_bytes(&self) -> &[u8] {
+        &self.0[1..1 + cin_len(self.cin())]
+    }
+}
+
+/// Number of MIDI bytes in a packet with the code index number `cin`
+pub fn cin_len(cin: u8) -> usize {
+    match cin {
+        // single byte, SysEx ending with a single byte
+        0x5 | 0xF => 1,
+        // 2-byte system common, SysEx ending with 2 bytes, program change, channel pressure
+        0x2 | 0x6 | 0xC | 0xD => 2,
+        // 3-byte system common, SysEx start or continuation, SysEx ending with
+        // 3 bytes, the other channel voice messages
+        0x3 | 0x4 | 0x7..=0xB | 0xE => 3,
+        // reserved (miscellaneous function codes, cable events)
+        _ => 0,
+    }
+}
+
+/// The packets carrying a message, several ones for a SysEx
+pub struct UsbMidiPackets {
+    cable: u8,
+    bytes: [u8; MidiMsg::MAX_LEN],
+    len: usize,
+    pos: usize,
+    // CIN of a message fitting a single packet, None for a SysEx
+    cin: Option<u8>,
+}
+
+impl Default for UsbMidiPackets {
+    fn default() -> Self {
+        Self {
+            cable: 0,
+            bytes: [0; MidiMsg::MAX_LEN],
+            len: 0,
+            pos: 0,
+            cin: None,
+        }
+    }
+}
+
+impl UsbMidiPackets {
+    pub fn encode(msg: &MidiMsg, cable: u8) -> Self {
+        let mut packets = Self {
+            cable,
+            ..Self::default()
+        };
+        packets.len = msg.serialize(&mut packets.bytes);
+        packets.cin = match msg {
+            MidiMsg::SysEx(_) => None,
+            MidiMsg::TuneRequest => Some(0x5),
+            MidiMsg::TimeCodeQuarterFrame { .. } | MidiMsg::SongSelect { .. } => Some(0x2),
+            MidiMsg::SongPosition { .. } => Some(0x3),
+            // channel voice messages: the upper nibble of the status
+            _ if msg.channel().is_some() => Some(msg.status() >> 4),
+            // real time
+            _ => Some(0xF),
+        };
+        packets
+    }
+}
+
+impl Iterator for UsbMidiPackets {
+    type Item = UsbMidiPacket;
+
+    fn next(&mut self) -> Option<UsbMidiPacket> {
+        let rest = self.len - self.pos;
+        if rest == 0 {
+            return None;
+        }
+        let n = rest.min(3);
+        let cin = match self.cin {
+            Some(cin) => cin,
+            // SysEx start or continuation
+            None if rest > 3 => 0x4,
+            // SysEx end with 1, 2 or 3 bytes
+            None => 0x4 + n as u8,
+        };
+        let packet = UsbMidiPacket::new(self.cable, cin, &self.bytes[self.pos..self.pos + n]);
+        self.pos += n;
+        Some(packet)
+    }
+}
+
+/// Decoder of the packets received, with separate state (SysEx in progress)
+/// for each of `USB_MIDI_CABLES` cables, packets for other cables are ignored
+pub struct UsbMidiDecoder {
+    parsers: [MidiParser; USB_MIDI_CABLES],
+}
+
+impl Default for UsbMidiDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UsbMidiDecoder {
+    pub fn new() -> Self {
+        Self {
+            parsers: core::array::from_fn(|_| MidiParser::new()),
+        }
+    }
+    pub fn reset(&mut self) {
+        for parser in self.parsers.iter_mut() {
+            parser.reset();
+        }
+    }
+    /// Messages completed by the bytes of `packet`, at most one unless it is malformed
+    pub fn decode(&mut self, packet: &UsbMidiPacket) -> [Option<MidiMsg>; 3] {
+        let mut msgs = [None; 3];
+        let Some(parser) = self.parsers.get_mut(packet.cable() as usize) else {
+            return msgs;
+        };
+        let cin = packet.cin();
+        if cin_len(cin) == 0 {
+            return msgs;
+        }
+        if !matches_cin(cin, packet.midi_bytes()) {
+            debug!("USB MIDI: malformed packet {}", packet);
+            parser.reset();
+            return msgs;
+        }
+        for (msg, &byte) in msgs.iter_mut().zip(packet.midi_bytes()) {
+            *msg = parser.push(byte);
+        }
+        // a whole message in a packet, the next packet must not continue it with
+        // running status, only a SysEx goes on over several packets
+        if !matches!(cin, 0x4..=0x7 | 0xF) {
+            parser.reset();
+        }
+        msgs
+    }
+}
+
+/// The `bytes` of a packet are what its code index number says
+fn matches_cin(cin: u8, bytes: &[u8]) -> bool {
+    let is_data = |bytes: &[u8]| bytes.iter().all(|&byte| byte < 0x80);
+    let Some((&first, rest)) = bytes.split_first() else {
+        return false;
+    };
+    match cin {
+        // system common with 1 or 2 data bytes
+        0x2 | 0x3 => matches!(first, 0xF1..=0xF6) && is_data(rest),
+        // SysEx start or continuation
+        0x4 => (first == 0xF0 || first < 0x80) && is_data(rest),
+        // single byte system common or SysEx end
+        0x5 => first == 0xF6 || first == 0xF7,
+        // SysEx end with 2 or 3 bytes
+        0x6 | 0x7 => match rest.split_last() {
+            Some((0xF7, middle)) => (first == 0xF0 || first < 0x80) && is_data(middle),
+            _ => false,
+        },
+        // channel voice, the CIN is the upper nibble of the status
+        0x8..=0xE => first >> 4 == cin && is_data(rest),
+        // single byte
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::SysEx;
+
+    fn msg(bytes: &[u8]) -> MidiMsg {
+        MidiMsg::from_bytes(bytes).unwrap()
+    }
+
+    fn sysex(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0xF0];
+        bytes.extend((0..len as u8).map(|i| i + 1));
+        bytes.push(0xF7);
+        bytes
+    }
+
+    fn encode(bytes: &[u8], cable: u8) -> Vec<[u8; 4]> {
+        UsbMidiPackets::encode(&msg(bytes), cable)
+            .map(|packet| packet.0)
+            .collect()
+    }
+
+    fn decode(decoder: &mut UsbMidiDecoder, packets: &[[u8; 4]]) -> Vec<MidiMsg> {
+        packets
+            .iter()
+            .flat_map(|&packet| decoder.decode(&UsbMidiPacket(packet)))
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn packet_fields() {
+        let packet = UsbMidiPacket::new(0x1F, 0x19, &[0x90, 60, 100, 1]);
+        assert_eq!(packet.0, [0xF9, 0x90, 60, 100]);
+        assert_eq!((packet.cable(), packet.cin()), (15, 9));
+        assert_eq!(packet.midi_bytes(), [0x90, 60, 100]);
+        let packet = UsbMidiPacket::new(2, 0xC, &[0xC0, 5]);
+        assert_eq!(packet.0, [0x2C, 0xC0, 5, 0]);
+        assert_eq!(packet.midi_bytes(), [0xC0, 5]);
+        // reserved, no MIDI bytes
+        assert!(UsbMidiPacket::new(0, 0x1, &[1, 2, 3])
+            .midi_bytes()
+            .is_empty());
+    }
+
+    #[test]
+    fn cin_lengths() {
+        let lengths: Vec<usize> = (0..16).map(cin_len).collect();
+        assert_eq!(lengths, [0, 0, 2, 3, 3, 1, 2, 3, 3, 3, 3, 3, 2, 2, 3, 1]);
+    }
+
+    #[test]
+    fn every_cin_of_a_single_packet_message() {
+        let cases: [(&[u8], u8); 17] = [
+            (&[0xF1, 0x35], 0x2),
+            (&[0xF3, 3], 0x2),
+            (&[0xF2, 1, 2], 0x3),
+            (&[0xF6], 0x5),
+            (&[0xF0, 0xF7], 0x6),
+            (&[0xF0, 1, 0xF7], 0x7),
+            (&[0x81, 60, 0], 0x8),
+            (&[0x92, 60, 100], 0x9),
+            (&[0xA3, 60, 10], 0xA),
+            (&[0xB4, 7, 100], 0xB),
+            (&[0xC5, 5], 0xC),
+            (&[0xD6, 50], 0xD),
+            (&[0xE7, 0, 0x40], 0xE),
+            (&[0xF8], 0xF),
+            (&[0xFA], 0xF),
+            (&[0xFE], 0xF),
+            (&[0xFF], 0xF),
+        ];
+        for (bytes, cin) in cases {
+            let mut packet = [cin, 0, 0, 0];
+            packet[1..1 + bytes.len()].copy_from_slice(bytes);
+            assert_eq!(encode(bytes, 0), [packet], "{:02x?}", bytes);
+            assert!(decode(&mut UsbMidiDecoder::new(), &[packet]) == [msg(bytes)]);
+        }
+    }
+
+    #[test]
+    fn sysex_segmentation() {
+        for len in 0..=SYSEX_MAX_LEN {
+            let bytes = sysex(len);
+            let packets = encode(&bytes, 0);
+            assert_eq!(packets.len(), bytes.len().div_ceil(3), "length {}", len);
+            let (last, start) = packets.split_last().unwrap();
+            for packet in start {
+                assert_eq!(packet[0], 0x4);
+            }
+            let rest = bytes.len() - 3 * start.len();
+            assert_eq!(last[0], 0x4 + rest as u8);
+            assert_eq!(last[1 + rest..], [0, 0, 0][rest..]);
+            let carried: Vec<u8> = packets
+                .iter()
+                .flat_map(|&p| UsbMidiPacket(p).midi_bytes().to_vec())
+                .collect();
+            assert_eq!(carried, bytes);
+            assert!(decode(&mut UsbMidiDecoder::new(), &packets) == [msg(&bytes)]);
+        }
+        assert_eq!(
+            encode(&sysex(5), 0),
+            [[0x4, 0xF0, 1, 2], [0x4, 3, 4, 5], [0x5, 0xF7, 0, 0]]
+        );
+        assert_eq!(encode(&sysex(3), 0), [[0x4, 0xF0, 1, 2], [0x6, 3, 0xF7, 0]]);
+        assert_eq!(encode(&sysex(4), 0), [[0x4, 0xF0, 1, 2], [0x7, 3, 4, 0xF7]]);
+    }
+
+    #[test]
+    fn cables() {
+        for cable in 0..16 {
+            for packet in encode(&sysex(7), cable) {
+                assert_eq!(UsbMidiPacket(packet).cable(), cable);
+            }
+            assert_eq!(encode(&[0x90, 60, 100], cable)[0][0], cable << 4 | 0x9);
+        }
+        let mut decoder = UsbMidiDecoder::new();
+        let other = USB_MIDI_CABLES as u8;
+        assert!(decode(&mut decoder, &encode(&[0x90, 60, 100], other)).is_empty());
+        // a SysEx in progress is not disturbed by the packets of another cable
+        let packets = encode(&sysex(6), 0);
+        let mut mixed = vec![packets[0]];
+        mixed.extend(encode(&sysex(2), other));
+        mixed.extend(&packets[1..]);
+        assert!(decode(&mut decoder, &mixed) == [msg(&sysex(6))]);
+    }
+
+    #[test]
+    fn realtime_inside_sysex() {
+        let packets = encode(&sysex(4), 0);
+        let input = [packets[0], [0xF, 0xF8, 0, 0], packets[1]];
+        let msgs = decode(&mut UsbMidiDecoder::new(), &input);
+        assert!(msgs == [MidiMsg::TimingClock, msg(&sysex(4))]);
+    }
+
+    #[test]
+    fn no_running_status_between_packets() {
+        let mut decoder = UsbMidiDecoder::new();
+        assert!(decode(&mut decoder, &[[0x9, 0x90, 60, 100]]) == [msg(&[0x90, 60, 100])]);
+        // data only, as a SysEx continuation or a single byte
+        assert!(decode(&mut decoder, &[[0x4, 61, 100, 0]]).is_empty());
+        assert!(decode(&mut decoder, &[[0xF, 61, 0, 0], [0xF, 100, 0, 0]]).is_empty());
+        // a channel voice CIN without its status
+        assert!(decode(&mut decoder, &[[0x9, 61, 100, 0]]).is_empty());
+        assert!(decode(&mut decoder, &[[0x9, 0x90, 61, 100]]) == [msg(&[0x90, 61, 100])]);
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let mut decoder = UsbMidiDecoder::new();
+        let bad = [
+            // status not matching the CIN
+            [0x8, 0x90, 60, 100],
+            [0x2, 0xF2, 1, 0],
+            [0x3, 0x90, 1, 2],
+            [0x5, 0xF8, 0, 0],
+            // a status byte in the data
+            [0xB, 0xB0, 0x80, 1],
+            [0x4, 0xF0, 1, 0x90],
+            // SysEx end without F7
+            [0x6, 1, 2, 0],
+            [0x7, 1, 2, 3],
+            // reserved
+            [0x0, 0x90, 60, 100],
+            [0x1, 0x90, 60, 100],
+        ];
+        for packet in bad {
+            assert!(
+                decode(&mut decoder, &[packet]).is_empty(),
+                "{:02x?}",
+                packet
+            );
+        }
+        // a malformed packet in the middle drops the SysEx in progress
+        let packets = encode(&sysex(6), 0);
+        let input = [packets[0], [0x7, 1, 2, 3], packets[1], packets[2]];
+        assert!(decode(&mut decoder, &input).is_empty());
+        // and the decoder carries on
+        assert!(decode(&mut decoder, &packets) == [msg(&sysex(6))]);
+    }
+
+    #[test]
+    fn sysex_too_long_is_dropped() {
+        let mut bytes = vec![0xF0];
+        bytes.extend([1; SYSEX_MAX_LEN + 1]);
+        bytes.push(0xF7);
+        let packets: Vec<[u8; 4]> = bytes
+            .chunks(3)
+            .map(|chunk| {
+                let cin = match chunk.last() {
+                    Some(0xF7) => 0x4 + chunk.len() as u8,
+                    _ => 0x4,
+                };
+                UsbMidiPacket::new(0, cin, chunk).0
+            })
+            .collect();
+        let mut decoder = UsbMidiDecoder::new();
+        assert!(decode(&mut decoder, &packets).is_empty());
+        let empty = MidiMsg::SysEx(SysEx::default());
+        assert!(decode(&mut decoder, &encode(&[0xF0, 0xF7], 0)) == [empty]);
+    }
+}
@@ -0,0 +1,112 @@
+// Note-on velocity from the touch onset dynamics
+
+use crate::config::*;
+use crate::math::isqrt;
+use crate::midi::U7;
+
+pub struct VelocityTracker {
+    curve: VelocityCurve,
+    prev_levels: [u32; NUM_SENSORS],
+    levels: [u32; NUM_SENSORS],
+}
+
+impl VelocityTracker {
+    pub fn new(curve: VelocityCurve) -> Self {
+        Self {
+            curve,
+            prev_levels: [0; NUM_SENSORS],
+            levels: [0; NUM_SENSORS],
+        }
+    }
+    /// Analog levels are only needed when velocity is not fixed
+    pub fn needs_levels(&self) -> bool {
+        !matches!(self.curve, VelocityCurve::Fixed(_))
+    }
+    /// Feed sensor levels (permile, see `TouchSensors::levels()`) from a single scan
+    pub fn update(&mut self, levels: &[u32; NUM_SENSORS]) {
+        self.prev_levels = self.levels;
+        self.levels = *levels;
+    }
+    /// Velocity of a note triggered by sensor `i` which has just crossed
+    /// `threshold_level` (permile)
+    pub fn velocity(&self, i: usize, threshold_level: u32) -> U7 {
+        let level = self.levels[i];
+        let speed = level.saturating_sub(self.prev_levels[i]);
+        let depth = if threshold_level < 1000 {
+            level.saturating_sub(threshold_level) * 1000 / (1000 - threshold_level)
+        } else {
+            0
+        };
+        let raw = ((speed * VELOCITY_SPEED_WEIGHT + depth * VELOCITY_DEPTH_WEIGHT) / 100).min(1000);
+        let shaped = match self.curve {
+            VelocityCurve::Fixed(velocity) => return velocity,
+            VelocityCurve::Linear => raw,
+            VelocityCurve::Soft => isqrt(raw * 1000),
+            VelocityCurve::Hard => raw * raw / 1000,
+        };
+        U7::saturating((1 + shaped * 126 / 1000) as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Velocity of sensor 0 going from `from` to `to` in a scan, threshold at 500
+    fn velocity(curve: VelocityCurve, from: u32, to: u32) -> U7 {
+        let mut tracker = VelocityTracker::new(curve);
+        let mut levels = [0; NUM_SENSORS];
+        levels[0] = from;
+        tracker.update(&levels);
+        levels[0] = to;
+        tracker.update(&levels);
+        tracker.velocity(0, 500)
+    }
+
+    #[test]
+    fn fixed_velocity_needs_no_levels() {
+        let tracker = VelocityTracker::new(VelocityCurve::Fixed(U7::saturating(90)));
+        assert!(!tracker.needs_levels());
+        assert!(tracker.velocity(0, 500) == U7::saturating(90));
+        assert!(VelocityTracker::new(VelocityCurve::Linear).needs_levels());
+    }
+
+    #[test]
+    fn full_range() {
+        for curve in [
+            VelocityCurve::Linear,
+            VelocityCurve::Soft,
+            VelocityCurve::Hard,
+        ] {
+            assert!(velocity(curve, 0, 1000) == U7::MAX);
+            assert!(velocity(curve, 500, 500) == U7::saturating(1));
+        }
+    }
+
+    #[test]
+    fn faster_and_deeper_is_louder() {
+        let slow = velocity(VelocityCurve::Linear, 500, 600);
+        let fast = velocity(VelocityCurve::Linear, 0, 600);
+        let deep = velocity(VelocityCurve::Linear, 500, 900);
+        assert!(slow < fast);
+        assert!(slow < deep);
+    }
+
+    #[test]
+    fn curves() {
+        let soft = velocity(VelocityCurve::Soft, 500, 700);
+        let linear = velocity(VelocityCurve::Linear, 500, 700);
+        let hard = velocity(VelocityCurve::Hard, 500, 700);
+        assert!(soft > linear);
+        assert!(linear > hard);
+    }
+
+    #[test]
+    fn threshold_at_the_top() {
+        let mut tracker = VelocityTracker::new(VelocityCurve::Linear);
+        tracker.update(&[1000; NUM_SENSORS]);
+        tracker.update(&[1000; NUM_SENSORS]);
+        // no movement and no depth past a threshold that cannot be exceeded
+        assert!(tracker.velocity(0, 1000) == U7::saturating(1));
+    }
+}
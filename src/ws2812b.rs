@@ -8,6 +8,7 @@ use fixed::traits::ToFixed;
 use fixed_macro::types::U56F8;
 
 use crate::board::LedsPio;
+use crate::keyboard::LedStrip;
 
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => pio::InterruptHandler<peripherals::PIO0>;
@@ -60,3 +61,9 @@ impl WS2812B {
         self.last_write = Instant::now();
     }
 }
+
+impl LedStrip for WS2812B {
+    async fn write(&mut self, colors: &[u32]) {
+        WS2812B::write(self, colors).await
+    }
+}